@@ -0,0 +1,810 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Virtual archive files of any revision, like `git archive` would write.
+
+use flate2;
+use fuse::{self, FileType};
+use git2;
+use libc;
+use std::cmp;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+use time;
+
+use attr;
+use cache::{Key, SharedCache};
+use filter::Filter;
+use inode;
+use inode::{FileAttr, Id, Inode};
+use reference;
+use trace;
+
+
+/// Like git archive, apply a umask of 002 to the modes within archives.
+const FILE_MODE: u32 = 0o664;
+const EXEC_MODE: u32 = 0o775;
+const LINK_MODE: u32 = 0o777;
+
+const BLOCK: u64 = 512;
+
+
+/// The kinds of archive that can be generated
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+const FORMATS: [Format; 3] = [Format::Tar, Format::TarGz, Format::Zip];
+
+impl Format {
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Format::Tar => ".tar",
+            Format::TarGz => ".tar.gz",
+            Format::Zip => ".zip",
+        }
+    }
+
+    /// Split an archive file name into its revision and format.
+    fn parse(name: &str) -> Option<(&str, Format)> {
+        FORMATS.iter()
+            .find(|format| name.ends_with(format.suffix()) && name.len() > format.suffix().len())
+            .map(|&format| (&name[..name.len() - format.suffix().len()], format))
+    }
+}
+
+
+/// The directory of archives, offering `<rev>.tar`, `<rev>.tar.gz` and `<rev>.zip` for any
+/// revision.  Only HEAD, branches and tags are listed, but any revspec can be looked up by
+/// name, escaped with `reference::escape` to fit in one file name.
+pub struct ArchiveDir;
+
+impl ArchiveDir {
+    pub fn new() -> Box<Inode+'static> {
+        Box::new(ArchiveDir)
+    }
+
+    fn entries(repo: &git2::Repository) -> Vec<(String, Id)> {
+        let mut revs = Vec::new();
+        if let Some(oid) = repo.head().ok().and_then(|head| head.target()) {
+            revs.push(("HEAD".to_string(), oid));
+        }
+        if let Ok(references) = repo.references() {
+            for reference in references.filter_map(|r| r.ok()) {
+                let name = match reference.name() {
+                    Some(name) if name.starts_with("refs/heads/") => &name["refs/heads/".len()..],
+                    Some(name) if name.starts_with("refs/tags/") => &name["refs/tags/".len()..],
+                    _ => continue,
+                };
+                if let Some(oid) = reference::peel(repo, &reference) {
+                    revs.push((reference::escape(name), oid));
+                }
+            }
+        }
+
+        let mut entries = Vec::with_capacity(revs.len() * FORMATS.len());
+        for (rev, oid) in revs {
+            for &format in FORMATS.iter() {
+                entries.push((format!("{}{}", rev, format.suffix()), Id::Archive(oid, format)));
+            }
+        }
+        entries
+    }
+}
+
+impl Inode for ArchiveDir {
+    fn lookup(&mut self, repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        let (rev, format) = try!(name.to_str().and_then(Format::parse).ok_or(libc::ENOENT));
+        let rev = reference::unescape(rev);
+        let object = try!(trace::git("revparse_single", || repo.revparse_single(&rev))
+                          .map_err(|_| libc::ENOENT));
+        let object = try!(object.peel(git2::ObjectType::Commit)
+                          .or_else(|_| object.peel(git2::ObjectType::Tree))
+                          .map_err(|_| libc::ENOENT));
+        Ok(Id::Archive(object.id(), format))
+    }
+
+    fn getattr(&mut self, repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = ArchiveDir::entries(repo).len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for (name, id) in ArchiveDir::entries(repo).into_iter().skip(offset as usize) {
+            if add(id, FileType::RegularFile, Path::new(&name)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// What an archive member contains
+enum Content {
+    Directory,
+    Symlink(Vec<u8>),
+    Blob(git2::Oid),
+    /// Content that had to be rewritten, i.e. for `export-subst`
+    Bytes(Vec<u8>),
+}
+
+/// One member of an archive
+struct Entry {
+    path: Vec<u8>,
+    mode: u32,
+    size: u64,
+    content: Content,
+}
+
+
+/// Walk a tree in the same order as git archive, collecting its entries.
+fn walk(repo: &git2::Repository, root: &git2::Tree, tree: &git2::Tree, prefix: &Path,
+        rules: &attr::Rules, filter: Option<&Filter>, commit: Option<&git2::Commit>,
+        entries: &mut Vec<Entry>) -> Result<(), git2::Error> {
+    for e in tree.iter() {
+        let path = prefix.join(<OsStr as OsStrExt>::from_bytes(e.name_bytes()));
+        if rules.is_set(&path, "export-ignore") {
+            continue;
+        }
+        // Leave out whatever the mount hides, just like its trees do
+        let is_dir = e.kind() == Some(git2::ObjectType::Tree);
+        if filter.map_or(false, |filter| !filter.allows(&path, is_dir)) {
+            continue;
+        }
+        let mut name = path.as_os_str().as_bytes().to_vec();
+
+        match e.filemode() {
+            0o040000 => {
+                name.push(b'/');
+                entries.push(Entry { path: name, mode: EXEC_MODE, size: 0,
+                                     content: Content::Directory });
+                let subtree = try!(repo.find_tree(e.id()));
+                let rules = rules.descend(repo, root, &path);
+                try!(walk(repo, root, &subtree, &path, &rules, filter, commit, entries));
+            },
+            0o160000 => {
+                // Submodules are just empty directories
+                name.push(b'/');
+                entries.push(Entry { path: name, mode: EXEC_MODE, size: 0,
+                                     content: Content::Directory });
+            },
+            0o120000 => {
+                let blob = try!(repo.find_blob(e.id()));
+                entries.push(Entry { path: name, mode: LINK_MODE, size: 0,
+                                     content: Content::Symlink(blob.content().to_vec()) });
+            },
+            filemode => {
+                let mode = if filemode & 0o111 != 0 { EXEC_MODE } else { FILE_MODE };
+                let blob = try!(repo.find_blob(e.id()));
+                let entry = match commit {
+                    Some(commit) if rules.is_set(&path, "export-subst") => {
+                        let data = export_subst(commit, blob.content());
+                        Entry { path: name, mode: mode, size: data.len() as u64,
+                                content: Content::Bytes(data) }
+                    },
+                    _ => Entry { path: name, mode: mode, size: blob.content().len() as u64,
+                                 content: Content::Blob(e.id()) },
+                };
+                entries.push(entry);
+            },
+        }
+    }
+    Ok(())
+}
+
+
+/// Expand `$Format:...$` placeholders with details of the commit being archived.
+fn export_subst(commit: &git2::Commit, data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(b"$Format:") {
+            let start = i + b"$Format:".len();
+            if let Some(len) = data[start..].iter().take_while(|&&b| b != b'\n')
+                                            .position(|&b| b == b'$') {
+                output.extend_from_slice(&pretty(commit, &data[start..start + len]));
+                i = start + len + 1;
+                continue;
+            }
+        }
+        output.push(data[i]);
+        i += 1;
+    }
+    output
+}
+
+/// Format a signature's time like git's default date format.
+fn git_date(when: git2::Time) -> String {
+    let local = when.seconds() + when.offset_minutes() as i64 * 60;
+    let tm = time::at_utc(time::Timespec::new(local, 0));
+    let date = time::strftime("%a %b %e %H:%M:%S %Y", &tm).unwrap_or_else(|_| String::new());
+    let sign = if when.offset_minutes() < 0 { '-' } else { '+' };
+    let offset = when.offset_minutes().abs();
+    format!("{} {}{:02}{:02}", date, sign, offset / 60, offset % 60)
+}
+
+/// Format commit details, with a subset of git's pretty-format placeholders.
+fn pretty(commit: &git2::Commit, format: &[u8]) -> Vec<u8> {
+    let short = |oid: git2::Oid| oid.to_string()[..7].to_string();
+    let author = commit.author();
+    let committer = commit.committer();
+
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' || i + 1 == format.len() {
+            output.push(format[i]);
+            i += 1;
+            continue;
+        }
+
+        // Two-letter placeholders for the author and committer
+        let person = match format[i + 1] {
+            b'a' => Some(&author),
+            b'c' => Some(&committer),
+            _ => None,
+        };
+        if let (Some(person), Some(&field)) = (person, format.get(i + 2)) {
+            let text = match field {
+                b'n' => Some(person.name().unwrap_or("").to_string()),
+                b'e' => Some(person.email().unwrap_or("").to_string()),
+                b'd' => Some(git_date(person.when())),
+                b't' => Some(person.when().seconds().to_string()),
+                _ => None,
+            };
+            if let Some(text) = text {
+                output.extend_from_slice(text.as_bytes());
+                i += 3;
+                continue;
+            }
+        }
+
+        let text = match format[i + 1] {
+            b'H' => commit.id().to_string(),
+            b'h' => short(commit.id()),
+            b'T' => commit.tree_id().to_string(),
+            b't' => short(commit.tree_id()),
+            b'P' => commit.parent_ids().map(|p| p.to_string()).collect::<Vec<_>>().join(" "),
+            b'p' => commit.parent_ids().map(&short).collect::<Vec<_>>().join(" "),
+            b's' => commit.summary().unwrap_or("").to_string(),
+            b'n' => "\n".to_string(),
+            b'%' => "%".to_string(),
+            _ => {
+                // Unknown placeholders are left as they are
+                output.push(b'%');
+                i += 1;
+                continue;
+            },
+        };
+        output.extend_from_slice(text.as_bytes());
+        i += 2;
+    }
+    output
+}
+
+
+/// Write a number as a NUL-terminated octal field.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:01$o}", value, field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+/// Build a single ustar header block.
+fn ustar_block(name: &[u8], prefix: &[u8], mode: u32, size: u64, mtime: i64,
+               typeflag: u8, linkname: &[u8]) -> Vec<u8> {
+    let mut block = vec![0; BLOCK as usize];
+    block[0..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], mode as u64);
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    octal(&mut block[124..136], size);
+    octal(&mut block[136..148], if mtime > 0 { mtime as u64 } else { 0 });
+    block[156] = typeflag;
+    block[157..157 + linkname.len()].copy_from_slice(linkname);
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[265..269].copy_from_slice(b"root");
+    block[297..301].copy_from_slice(b"root");
+    block[345..345 + prefix.len()].copy_from_slice(prefix);
+
+    // The checksum is computed with its own field as spaces
+    for b in &mut block[148..156] {
+        *b = b' ';
+    }
+    let sum = block.iter().map(|&b| b as u64).sum::<u64>();
+    let digits = format!("{:06o}", sum);
+    block[148..154].copy_from_slice(digits.as_bytes());
+    block[154] = 0;
+    block
+}
+
+/// Add a pax extended header record, whose length counts itself.
+fn pax_record(records: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    records.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    records.extend_from_slice(value);
+    records.push(b'\n');
+}
+
+/// Build the complete header for an entry, with a pax extended header first if it doesn't fit
+/// within plain ustar limits.
+fn tar_header(entry: &Entry, mtime: i64) -> Vec<u8> {
+    let mut pax = Vec::new();
+
+    // Long paths may fit by splitting at a slash into the prefix field
+    let path = &entry.path[..];
+    let (prefix, name) = if path.len() <= 100 {
+        (&path[..0], path)
+    } else {
+        let trimmed = if path.ends_with(b"/") { &path[..path.len() - 1] } else { path };
+        let split = trimmed.iter().enumerate().rev()
+            .filter(|&(i, &b)| b == b'/' && i <= 155 && path.len() - i - 1 <= 100)
+            .map(|(i, _)| i)
+            .next();
+        match split {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => {
+                pax_record(&mut pax, "path", path);
+                (&path[..0], &path[..100])
+            },
+        }
+    };
+
+    let (typeflag, linkname) = match entry.content {
+        Content::Directory => (b'5', &[][..]),
+        Content::Symlink(ref target) => (b'2', &target[..]),
+        _ => (b'0', &[][..]),
+    };
+    let linkname = if linkname.len() > 100 {
+        pax_record(&mut pax, "linkpath", linkname);
+        &linkname[..100]
+    } else {
+        linkname
+    };
+
+    let size = if entry.size > 0o77777777777 {
+        pax_record(&mut pax, "size", entry.size.to_string().as_bytes());
+        0
+    } else {
+        entry.size
+    };
+
+    let mut header = Vec::new();
+    if !pax.is_empty() {
+        header.extend(ustar_block(b"pax_header", b"", 0o666, pax.len() as u64, mtime,
+                                  b'x', b""));
+        let padded = padded(pax.len() as u64);
+        header.extend(pax);
+        header.resize(padded as usize + BLOCK as usize, 0);
+    }
+    header.extend(ustar_block(name, prefix, entry.mode, size, mtime, typeflag, linkname));
+    header
+}
+
+/// Round a size up to whole tar blocks.
+fn padded(size: u64) -> u64 {
+    (size + BLOCK - 1) / BLOCK * BLOCK
+}
+
+
+/// A tar stream laid out ahead of time, so any part can be produced on demand
+struct TarLayout {
+    /// Each entry's starting offset and header; its content follows the header
+    starts: Vec<(u64, Vec<u8>)>,
+    size: u64,
+}
+
+impl TarLayout {
+    fn new(entries: &[Entry], mtime: i64) -> TarLayout {
+        let mut offset = 0;
+        let starts = entries.iter().map(|entry| {
+            let header = tar_header(entry, mtime);
+            let start = offset;
+            offset += header.len() as u64 + padded(entry.size);
+            (start, header)
+        }).collect();
+
+        // The end of the archive is marked by two zeroed blocks
+        TarLayout { starts: starts, size: offset + 2 * BLOCK }
+    }
+}
+
+
+/// A virtual archive of a commit or tree
+pub struct Archive {
+    oid: git2::Oid,
+    format: Format,
+    mtime: i64,
+    entries: Option<Vec<Entry>>,
+    layout: Option<TarLayout>,
+    /// The generated archive, for formats which can't be produced piecemeal
+    data: Option<Arc<Vec<u8>>>,
+    size: Option<u64>,
+    /// The most recently read blob, since reads tend to be sequential
+    blob: Option<(git2::Oid, Vec<u8>)>,
+    buffer: Vec<u8>,
+    filter: Option<Arc<Filter>>,
+    key: Key,
+    cache: SharedCache,
+}
+
+impl Archive {
+    pub fn new(repo: &git2::Repository, oid: git2::Oid, format: Format,
+               filter: Option<Arc<Filter>>, cache: SharedCache) -> Box<Inode+'static> {
+        let key = Key::Archive(repo.path().to_path_buf(), oid, format);
+        let known = cache.lock().unwrap().archive(&key);
+
+        // Commits give the archive their time, otherwise use the current time like git, but
+        // an archive that was generated before keeps its time, so it comes out the same
+        let mtime = match (known, repo.find_commit(oid)) {
+            (Some((_, mtime)), _) => mtime,
+            (None, Ok(commit)) => commit.time().seconds(),
+            (None, Err(_)) => time::get_time().sec,
+        };
+        Box::new(Archive {
+            oid: oid,
+            format: format,
+            mtime: mtime,
+            entries: None,
+            layout: None,
+            data: None,
+            size: known.map(|(size, _)| size),
+            blob: None,
+            buffer: Vec::new(),
+            filter: filter,
+            key: key,
+            cache: cache,
+        })
+    }
+
+    /// Record the size once it's known, here and in the cache for any other inode of the same
+    /// archive.
+    fn set_size(&mut self, size: u64) {
+        self.size = Some(size);
+        self.cache.lock().unwrap().set_archive(self.key.clone(), size, self.mtime);
+    }
+
+    fn entries(&mut self, repo: &git2::Repository) -> Result<&[Entry], libc::c_int> {
+        if self.entries.is_none() {
+            let commit = repo.find_commit(self.oid).ok();
+            let oid = self.oid;
+            let object = try!(trace::git_errno("find_object", || repo.find_object(oid, None)));
+            let tree = try!(object.peel(git2::ObjectType::Tree).ok()
+                            .and_then(|tree| tree.into_tree().ok())
+                            .ok_or(libc::EIO));
+            let rules = attr::Rules::new(repo, &tree);
+            let mut entries = Vec::new();
+            let filter = self.filter.as_ref().map(|filter| &**filter);
+            try!(walk(repo, &tree, &tree, &PathBuf::new(), &rules, filter, commit.as_ref(),
+                      &mut entries)
+                 .map_err(|e| trace::errno("walk", &e)));
+            self.entries = Some(entries);
+        }
+        Ok(self.entries.as_ref().unwrap())
+    }
+
+    fn layout(&mut self, repo: &git2::Repository) -> Result<(), libc::c_int> {
+        if self.layout.is_none() {
+            let mtime = self.mtime;
+            let layout = TarLayout::new(try!(self.entries(repo)), mtime);
+            if self.format == Format::Tar {
+                self.set_size(layout.size);
+            }
+            self.layout = Some(layout);
+        }
+        Ok(())
+    }
+
+    /// Copy part of the tar stream into `out`.
+    fn read_tar(&mut self, repo: &git2::Repository, mut offset: u64, size: u64,
+                out: &mut Vec<u8>) -> Result<(), libc::c_int> {
+        try!(self.layout(repo));
+        let layout = self.layout.as_ref().unwrap();
+        let entries = self.entries.as_ref().unwrap();
+        let end = cmp::min(offset + size, layout.size);
+
+        let mut i = match layout.starts.binary_search_by(|&(start, _)| start.cmp(&offset)) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1),
+        };
+        while offset < end {
+            let (start, header) = match layout.starts.get(i) {
+                Some(&(start, ref header)) => (start, header),
+                None => {
+                    // Past the last entry, only the zeroed trailer remains
+                    out.resize(out.len() + (end - offset) as usize, 0);
+                    break;
+                },
+            };
+            let entry = &entries[i];
+            let content_start = start + header.len() as u64;
+            let next = content_start + padded(entry.size);
+
+            if offset < content_start {
+                let from = (offset - start) as usize;
+                let to = (cmp::min(end, content_start) - start) as usize;
+                out.extend_from_slice(&header[from..to]);
+                offset = start + to as u64;
+            }
+            let content_end = content_start + entry.size;
+            if offset < end && offset < content_end {
+                let from = (offset - content_start) as usize;
+                let to = (cmp::min(end, content_end) - content_start) as usize;
+                match entry.content {
+                    Content::Blob(oid) => {
+                        let cached = match self.blob {
+                            Some((cached, _)) => cached == oid,
+                            None => false,
+                        };
+                        if !cached {
+                            let blob = try!(trace::git_errno("find_blob", || repo.find_blob(oid)));
+                            self.blob = Some((oid, blob.content().to_vec()));
+                        }
+                        let data = &self.blob.as_ref().unwrap().1;
+                        out.extend_from_slice(&data[from..to]);
+                    },
+                    Content::Bytes(ref data) => out.extend_from_slice(&data[from..to]),
+                    _ => (),
+                }
+                offset = content_start + to as u64;
+            }
+            if offset < end && offset < next {
+                let to = cmp::min(end, next);
+                out.resize(out.len() + (to - offset) as usize, 0);
+                offset = to;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Produce the whole archive in memory, for compressed and zip formats, or find it in the
+    /// cache if it was produced before.
+    fn generate(&mut self, repo: &git2::Repository) -> Result<(), libc::c_int> {
+        if self.data.is_some() || self.format == Format::Tar {
+            return Ok(());
+        }
+        let cache = self.cache.clone();
+        let data = try!(cache.lock().unwrap().get(self.key.clone(), || self.build(repo)));
+        self.set_size(data.len() as u64);
+        self.data = Some(data);
+        Ok(())
+    }
+
+    /// Build the whole archive.
+    fn build(&mut self, repo: &git2::Repository) -> Result<Vec<u8>, libc::c_int> {
+        Ok(match self.format {
+            Format::Tar => {
+                try!(self.layout(repo));
+                let size = self.layout.as_ref().unwrap().size;
+                let mut data = Vec::with_capacity(size as usize);
+                try!(self.read_tar(repo, 0, size, &mut data));
+                data
+            },
+            Format::TarGz => {
+                try!(self.layout(repo));
+                let size = self.layout.as_ref().unwrap().size;
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(),
+                                                                flate2::Compression::Default);
+                let chunk = 1 << 20;
+                let mut offset = 0;
+                let mut buffer = Vec::with_capacity(chunk as usize);
+                while offset < size {
+                    buffer.clear();
+                    try!(self.read_tar(repo, offset, chunk, &mut buffer));
+                    try!(encoder.write_all(&buffer).map_err(|_| libc::EIO));
+                    offset += chunk;
+                }
+                try!(encoder.finish().map_err(|_| libc::EIO))
+            },
+            Format::Zip => {
+                let mtime = self.mtime;
+                try!(write_zip(repo, try!(self.entries(repo)), mtime))
+            },
+        })
+    }
+}
+
+impl Inode for Archive {
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        // NB: Sizes aren't known until the archive is first opened, since even laying out a
+        // tar walks the whole tree, and listing archive/ shouldn't do that for every revision.
+        // After that, the cache remembers them.
+        if self.size.is_none() && self.layout.is_none() {
+            if let Some((size, mtime)) = self.cache.lock().unwrap().archive(&self.key) {
+                self.size = Some(size);
+                self.mtime = mtime;
+            }
+        }
+        let size = self.size.unwrap_or(0);
+        let mtime = time::Timespec::new(self.mtime, 0);
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            atime: mtime,
+            mtime: mtime,
+            ctime: mtime,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            ..attr
+        })
+    }
+
+    fn open(&mut self, repo: &git2::Repository, _flags: u32) -> Result<u32, libc::c_int> {
+        match self.format {
+            Format::Tar => try!(self.layout(repo)),
+            _ => try!(self.generate(repo)),
+        }
+        // The size may not have been known before, so bypass the page cache's idea of it
+        Ok(fuse::consts::FOPEN_DIRECT_IO)
+    }
+
+    fn read(&mut self, repo: &git2::Repository, offset: u64, size: u32
+           ) -> Result<&[u8], libc::c_int> {
+        if self.format == Format::Tar {
+            let mut buffer = mem::replace(&mut self.buffer, Vec::new());
+            buffer.clear();
+            try!(self.read_tar(repo, offset, size as u64, &mut buffer));
+            self.buffer = buffer;
+            return Ok(&self.buffer);
+        }
+
+        try!(self.generate(repo));
+        let data = self.data.as_ref().unwrap();
+        let start = cmp::min(offset, data.len() as u64) as usize;
+        let end = cmp::min(start + size as usize, data.len());
+        Ok(&data[start..end])
+    }
+
+    fn release(&mut self, _repo: &git2::Repository) -> Result<(), libc::c_int> {
+        self.data.take();
+        self.blob.take();
+        self.buffer = Vec::new();
+        Ok(())
+    }
+}
+
+
+/// Compute the CRC-32 used by zip.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn le16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn le32(out: &mut Vec<u8>, value: u32) {
+    le16(out, value as u16);
+    le16(out, (value >> 16) as u16);
+}
+
+/// Write a zip archive of stored (uncompressed) entries.
+fn write_zip(repo: &git2::Repository, entries: &[Entry], mtime: i64)
+             -> Result<Vec<u8>, libc::c_int> {
+    // MS-DOS timestamps can't go before 1980
+    let tm = time::at_utc(time::Timespec::new(mtime, 0));
+    let (dos_time, dos_date) = if tm.tm_year < 80 {
+        (0, (1 << 5) | 1)
+    } else {
+        (((tm.tm_hour << 11) | (tm.tm_min << 5) | (tm.tm_sec / 2)) as u16,
+         (((tm.tm_year - 80) << 9) | ((tm.tm_mon + 1) << 5) | tm.tm_mday) as u16)
+    };
+
+    let mut out = Vec::new();
+    let mut central = Vec::new();
+    for entry in entries {
+        let (data, kind) = match entry.content {
+            Content::Directory => (Vec::new(), 0o040000),
+            Content::Symlink(ref target) => (target.clone(), 0o120000),
+            Content::Blob(oid) => {
+                let blob = try!(trace::git_errno("find_blob", || repo.find_blob(oid)));
+                (blob.content().to_vec(), 0o100000)
+            },
+            Content::Bytes(ref data) => (data.clone(), 0o100000),
+        };
+        if data.len() > 0xffffffff || out.len() > 0xffffffff {
+            // Too big without zip64
+            return Err(libc::EFBIG);
+        }
+        let crc = crc32(&data);
+        let offset = out.len() as u32;
+        // Only claim names are UTF-8 when they are, or unzip would mangle the others
+        let flags = if str::from_utf8(&entry.path).is_ok() { 0x0800 } else { 0 };
+
+        // Local file header
+        le32(&mut out, 0x04034b50);
+        le16(&mut out, 10);
+        le16(&mut out, flags);
+        le16(&mut out, 0);
+        le16(&mut out, dos_time);
+        le16(&mut out, dos_date);
+        le32(&mut out, crc);
+        le32(&mut out, data.len() as u32);
+        le32(&mut out, data.len() as u32);
+        le16(&mut out, entry.path.len() as u16);
+        le16(&mut out, 0);
+        out.extend_from_slice(&entry.path);
+        out.extend_from_slice(&data);
+
+        // Central directory entry, made by unix to carry the mode
+        let mut external = (kind | entry.mode) << 16;
+        if kind == 0o040000 {
+            external |= 0x10;
+        }
+        le32(&mut central, 0x02014b50);
+        le16(&mut central, (3 << 8) | 10);
+        le16(&mut central, 10);
+        le16(&mut central, flags);
+        le16(&mut central, 0);
+        le16(&mut central, dos_time);
+        le16(&mut central, dos_date);
+        le32(&mut central, crc);
+        le32(&mut central, data.len() as u32);
+        le32(&mut central, data.len() as u32);
+        le16(&mut central, entry.path.len() as u16);
+        le16(&mut central, 0);
+        le16(&mut central, 0);
+        le16(&mut central, 0);
+        le16(&mut central, 0);
+        le32(&mut central, external);
+        le32(&mut central, offset);
+        central.extend_from_slice(&entry.path);
+    }
+
+    if entries.len() > 0xffff {
+        return Err(libc::EFBIG);
+    }
+    let central_offset = out.len() as u32;
+    let central_size = central.len() as u32;
+    out.extend(central);
+
+    // End of central directory
+    le32(&mut out, 0x06054b50);
+    le16(&mut out, 0);
+    le16(&mut out, 0);
+    le16(&mut out, entries.len() as u16);
+    le16(&mut out, entries.len() as u16);
+    le32(&mut out, central_size);
+    le32(&mut out, central_offset);
+    le16(&mut out, 0);
+    Ok(out)
+}
@@ -0,0 +1,424 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checkout conversions driven by `.gitattributes`: `text`/`eol` line endings,
+//! `ident` expansion, and `filter` smudge commands.
+
+use git2;
+use libc;
+use std::collections::hash_map;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use time;
+
+use glob;
+
+
+/// The value of a single attribute for a path
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Set,
+    Unset,
+    Value(String),
+}
+
+
+/// One line of an attributes file
+#[derive(Clone)]
+struct Rule {
+    /// The directory containing the attributes file, relative to the tree root
+    base: PathBuf,
+    pattern: Vec<u8>,
+    attrs: Vec<(String, Option<Value>)>,
+}
+
+impl Rule {
+    fn parse(base: &Path, line: &str) -> Option<Rule> {
+        let mut words = line.split_whitespace();
+        let pattern = match words.next() {
+            Some(pattern) if !pattern.starts_with('#') => pattern,
+            _ => return None,
+        };
+
+        let mut attrs = Vec::new();
+        for word in words {
+            if word.starts_with('-') {
+                attrs.push((word[1..].to_string(), Some(Value::Unset)));
+            } else if word.starts_with('!') {
+                attrs.push((word[1..].to_string(), None));
+            } else if let Some(eq) = word.find('=') {
+                let value = Value::Value(word[eq + 1..].to_string());
+                attrs.push((word[..eq].to_string(), Some(value)));
+            } else if word == "binary" {
+                // The only built-in macro
+                attrs.push(("diff".to_string(), Some(Value::Unset)));
+                attrs.push(("merge".to_string(), Some(Value::Unset)));
+                attrs.push(("text".to_string(), Some(Value::Unset)));
+            } else {
+                attrs.push((word.to_string(), Some(Value::Set)));
+            }
+        }
+
+        Some(Rule {
+            base: base.to_path_buf(),
+            pattern: pattern.as_bytes().to_vec(),
+            attrs: attrs,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let relative = match path.strip_prefix(&self.base) {
+            Ok(relative) => relative,
+            Err(_) => return false,
+        };
+
+        let mut pattern = &self.pattern[..];
+        if pattern.contains(&b'/') {
+            // Patterns with a slash are anchored to the attributes file's directory
+            if pattern.first() == Some(&b'/') {
+                pattern = &pattern[1..];
+            }
+            glob::matches(pattern, relative.as_os_str().as_bytes())
+        } else {
+            // Otherwise they only match the final name, at any depth
+            let name = path.file_name().map_or(&[][..], |name| name.as_bytes());
+            glob::matches(pattern, name)
+        }
+    }
+}
+
+
+/// Read the rules of a `.gitattributes` file in the given directory of a tree.
+fn tree_rules(repo: &git2::Repository, root: &git2::Tree, dir: &Path) -> Vec<Rule> {
+    let path = dir.join(".gitattributes");
+    root.get_path(&path).ok()
+        .and_then(|entry| repo.find_blob(entry.id()).ok())
+        .map(|blob| parse_rules(dir, blob.content()))
+        .unwrap_or_else(Vec::new)
+}
+
+fn parse_rules(base: &Path, content: &[u8]) -> Vec<Rule> {
+    String::from_utf8_lossy(content).lines()
+        .filter_map(|line| Rule::parse(base, line))
+        .collect()
+}
+
+
+/// The attribute rules that apply within one directory of a tree
+#[derive(Clone)]
+pub struct Rules {
+    /// Rules from .gitattributes files, from lowest to highest precedence
+    tree: Vec<Rule>,
+    /// Rules from GIT_DIR/info/attributes, which override all others
+    info: Vec<Rule>,
+}
+
+impl Rules {
+    /// Gather the rules for the top of a root tree.
+    pub fn new(repo: &git2::Repository, root: &git2::Tree) -> Rules {
+        let mut info = Vec::new();
+        if let Ok(mut file) = fs::File::open(repo.path().join("info").join("attributes")) {
+            let mut content = Vec::new();
+            if file.read_to_end(&mut content).is_ok() {
+                info = parse_rules(Path::new(""), &content);
+            }
+        }
+        Rules {
+            tree: tree_rules(repo, root, Path::new("")),
+            info: info,
+        }
+    }
+
+    /// Gather the rules for a subdirectory `dir` of the root tree, given the rules of its parent.
+    pub fn descend(&self, repo: &git2::Repository, root: &git2::Tree, dir: &Path) -> Rules {
+        let mut rules = self.clone();
+        rules.tree.extend(tree_rules(repo, root, dir));
+        rules
+    }
+
+    /// Find all of the attributes of a path in this directory.
+    fn get(&self, path: &Path) -> hash_map::HashMap<String, Value> {
+        let mut attrs = hash_map::HashMap::new();
+        for rule in self.tree.iter().chain(&self.info).filter(|rule| rule.matches(path)) {
+            for &(ref name, ref value) in &rule.attrs {
+                match *value {
+                    Some(ref value) => { attrs.insert(name.clone(), value.clone()); },
+                    None => { attrs.remove(name); },
+                }
+            }
+        }
+        attrs
+    }
+
+    /// Check whether an attribute is set for a path in this directory.
+    pub fn is_set(&self, path: &Path, name: &str) -> bool {
+        self.get(path).get(name) == Some(&Value::Set)
+    }
+}
+
+
+/// The rules of each directory of root trees, so each `.gitattributes` is only parsed once
+#[derive(Default)]
+pub struct RulesCache {
+    rules: hash_map::HashMap<(git2::Oid, PathBuf), Rules>,
+}
+
+impl RulesCache {
+    /// Get the rules for directory `dir` of the tree `root`, gathering them if needed.
+    fn get(&mut self, repo: &git2::Repository, root: &git2::Tree, dir: &Path) -> &Rules {
+        let key = (root.id(), dir.to_path_buf());
+        if !self.rules.contains_key(&key) {
+            let rules = match dir.parent() {
+                Some(parent) => self.get(repo, root, parent).descend(repo, root, dir),
+                None => Rules::new(repo, root),
+            };
+            self.rules.insert(key.clone(), rules);
+        }
+        &self.rules[&key]
+    }
+
+    /// Forget all rules, to be gathered again when they're next used.
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+}
+
+
+/// Find the attributes of a path in the given root tree, as a checkout would.
+fn attributes(repo: &git2::Repository, root: &git2::Tree, path: &Path, rules: &mut RulesCache)
+              -> hash_map::HashMap<String, Value> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    rules.get(repo, root, dir).get(path)
+}
+
+
+/// How line endings are converted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Crlf {
+    /// Convert LF to CRLF
+    Text,
+    /// Convert LF to CRLF, only if the content looks like text
+    Auto,
+}
+
+
+/// An external smudge command, from `filter.<driver>.smudge`
+struct Filter {
+    smudge: String,
+    required: bool,
+    /// How long the command may run before it's killed
+    timeout: Duration,
+}
+
+
+/// The conversions that checking out one path would apply to its blob
+pub struct Conversion {
+    path: PathBuf,
+    crlf: Option<Crlf>,
+    ident: bool,
+    filter: Option<Filter>,
+}
+
+impl Conversion {
+    /// Work out the conversions for `path` within the tree `root`, or `None` if its content
+    /// would be checked out unchanged.  Filters named in `skip_filters` are ignored, for those
+    /// which GitFS handles on its own, and the others are killed after `filter_timeout`.
+    pub fn new(repo: &git2::Repository, root: git2::Oid, path: &Path, skip_filters: &[&str],
+               filter_timeout: time::Timespec, rules: &mut RulesCache) -> Option<Conversion> {
+        let root = match repo.find_tree(root) {
+            Ok(root) => root,
+            Err(_) => return None,
+        };
+        let attrs = attributes(repo, &root, path, rules);
+        let config = repo.config().ok();
+        let config_str = |name: &str| {
+            config.as_ref().and_then(|config| config.get_string(name).ok())
+        };
+
+        // Line endings, following git's own combination of attributes and configuration
+        let autocrlf = config_str("core.autocrlf").map(|s| s.to_lowercase());
+        let crlf_output = match attrs.get("eol") {
+            Some(&Value::Value(ref eol)) => eol == "crlf",
+            _ => match autocrlf.as_ref().map(|s| &s[..]) {
+                Some("true") => true,
+                Some("input") => false,
+                _ => config_str("core.eol").map_or(false, |eol| eol == "crlf"),
+            },
+        };
+        let text = match attrs.get("text") {
+            Some(&Value::Set) => Some(Crlf::Text),
+            Some(&Value::Unset) => None,
+            Some(&Value::Value(ref value)) if value == "auto" => Some(Crlf::Auto),
+            _ => if attrs.contains_key("eol") {
+                Some(Crlf::Text)
+            } else if autocrlf.as_ref().map_or(false, |s| s == "true") {
+                Some(Crlf::Auto)
+            } else {
+                None
+            },
+        };
+        let crlf = if crlf_output { text } else { None };
+
+        let ident = attrs.get("ident") == Some(&Value::Set);
+
+        let filter = match attrs.get("filter") {
+            Some(&Value::Value(ref driver)) if !skip_filters.contains(&&driver[..]) => {
+                config_str(&format!("filter.{}.smudge", driver)).map(|smudge| Filter {
+                    smudge: smudge,
+                    required: config.as_ref()
+                        .and_then(|c| c.get_bool(&format!("filter.{}.required", driver)).ok())
+                        .unwrap_or(false),
+                    timeout: Duration::new(filter_timeout.sec as u64, filter_timeout.nsec as u32),
+                })
+            },
+            _ => None,
+        };
+
+        if crlf.is_none() && !ident && filter.is_none() {
+            return None;
+        }
+        Some(Conversion {
+            path: path.to_path_buf(),
+            crlf: crlf,
+            ident: ident,
+            filter: filter,
+        })
+    }
+
+    /// Convert the content of blob `oid`, in the same order as git: ident, then line endings,
+    /// then any external filter.
+    pub fn apply(&self, oid: git2::Oid, mut data: Vec<u8>) -> Result<Vec<u8>, libc::c_int> {
+        if self.ident {
+            data = expand_ident(oid, &data);
+        }
+        match self.crlf {
+            Some(Crlf::Text) => data = to_crlf(&data),
+            Some(Crlf::Auto) if !is_binary(&data) && !has_crlf(&data) => data = to_crlf(&data),
+            _ => (),
+        }
+        if let Some(ref filter) = self.filter {
+            match try!(run_filter(&filter.smudge, &self.path, &data, filter.timeout)) {
+                Some(output) => data = output,
+                None if filter.required => return Err(libc::EIO),
+                None => (),
+            }
+        }
+        Ok(data)
+    }
+}
+
+
+/// Like git, guess that content is binary if there's a NUL early on.
+fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|&b| b == 0)
+}
+
+fn has_crlf(data: &[u8]) -> bool {
+    data.windows(2).any(|w| w == b"\r\n")
+}
+
+/// Convert lone LF to CRLF.
+fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(data.len());
+    let mut prev = 0;
+    for &b in data {
+        if b == b'\n' && prev != b'\r' {
+            output.push(b'\r');
+        }
+        output.push(b);
+        prev = b;
+    }
+    output
+}
+
+/// Expand `$Id$` and `$Id: ... $` keywords to `$Id: <oid> $`.
+fn expand_ident(oid: git2::Oid, data: &[u8]) -> Vec<u8> {
+    let expanded = format!("$Id: {} $", oid);
+    let mut output = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(b"$Id") {
+            let rest = &data[i + 3..];
+            let end = if rest.starts_with(b"$") {
+                Some(i + 4)
+            } else if rest.starts_with(b":") {
+                // The old expansion must end on the same line
+                rest.iter().take_while(|&&b| b != b'\n').position(|&b| b == b'$')
+                    .map(|pos| i + 3 + pos + 1)
+            } else {
+                None
+            };
+            if let Some(end) = end {
+                output.extend_from_slice(expanded.as_bytes());
+                i = end;
+                continue;
+            }
+        }
+        output.push(data[i]);
+        i += 1;
+    }
+    output
+}
+
+/// Run a smudge command through the shell, with `%f` replaced by the path, giving its output
+/// or `None` if it failed.  If it runs longer than `timeout`, it's killed and this fails with
+/// `EIO`, since every other request waits for it.
+fn run_filter(command: &str, path: &Path, data: &[u8], timeout: Duration
+             ) -> Result<Option<Vec<u8>>, libc::c_int> {
+    let quoted = format!("'{}'", path.to_string_lossy().replace("'", "'\\''"));
+    let command = command.replace("%f", &quoted);
+
+    let mut child = match Command::new("sh").arg("-c").arg(&command)
+        .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null())
+        .spawn() {
+        Ok(child) => child,
+        Err(_) => return Ok(None),
+    };
+
+    // Feed the input and collect the output in other threads, so a filter with lots of
+    // output can't deadlock, and one that never finishes can be given up on
+    let mut stdin = child.stdin.take().unwrap();
+    let input = data.to_vec();
+    let writer = thread::spawn(move || stdin.write_all(&input).is_ok());
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).ok().map(|_| output)
+    });
+
+    // NB: This polls waitpid itself, since `Child::try_wait` needs a newer Rust
+    let pid = child.id() as libc::pid_t;
+    let deadline = time::precise_time_ns() + timeout.as_secs() * 1_000_000_000 +
+        timeout.subsec_nanos() as u64;
+    let mut status = 0;
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) } {
+            0 if time::precise_time_ns() < deadline => thread::sleep(Duration::from_millis(1)),
+            0 => {
+                // NB: The threads are left to finish once the pipes close
+                warn!("filter `{}` timed out on {}", command, path.display());
+                child.kill().ok();
+                unsafe { libc::waitpid(pid, &mut status, 0) };
+                return Err(libc::EIO);
+            },
+            -1 => return Ok(None),
+            _ => break,
+        }
+    }
+    // A status of 0 means it exited successfully
+    let written = writer.join().unwrap_or(false);
+    match reader.join() {
+        Ok(Some(output)) if status == 0 && written => Ok(Some(output)),
+        _ => Ok(None),
+    }
+}
@@ -9,67 +9,190 @@
 use fuse::{self, FileType};
 use git2;
 use libc;
+use std::cmp;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
 
+use LfsPolicy;
+use attr;
+use cache::{Key, SharedCache};
 use inode;
+use lfs;
+use trace;
+
+/// Fill in the attributes of a blob whose content is `size` bytes.
+pub fn getattr(size: u64, attr: inode::FileAttr) -> inode::FileAttr {
+    inode::FileAttr {
+        size: size,
+        blocks: inode::st_blocks(size),
+        kind: FileType::RegularFile,
+        perm: 0o644,
+        ..attr
+    }
+}
+
 
 /// Git blobs are represented as files
 // FIXME needs context, e.g. permissions from TreeEntry and timestamps from Commit
 pub struct Blob {
     oid: git2::Oid,
     size: u64,
-    data: Option<Vec<u8>>,
+    data: Option<Arc<Vec<u8>>>,
+    cache: SharedCache,
+    /// The LFS pointer this content is, once it has been checked
+    lfs: Option<Option<lfs::Pointer>>,
+    lfs_policy: LfsPolicy,
+    lfs_file: Option<fs::File>,
+    lfs_buffer: Vec<u8>,
+    conversion: Option<attr::Conversion>,
+    converted_size: Option<u64>,
 }
 
 impl Blob {
-    pub fn new(blob: git2::Blob) -> Box<inode::Inode+'static> {
+    /// Create a blob of the given size, without loading its content.
+    pub fn new(oid: git2::Oid, size: u64, lfs_policy: LfsPolicy,
+               conversion: Option<attr::Conversion>, cache: SharedCache
+              ) -> Box<inode::Inode+'static> {
+        // Only content small enough to be an LFS pointer is worth checking, later on
+        let lfs = match lfs_policy {
+            LfsPolicy::Disabled => Some(None),
+            _ if size > lfs::MAX_POINTER_SIZE as u64 => Some(None),
+            _ => None,
+        };
         Box::new(Blob {
-            oid: blob.id(),
-            size: blob.content().len() as u64,
+            oid: oid,
+            size: size,
             data: None,
+            cache: cache,
+            lfs: lfs,
+            lfs_policy: lfs_policy,
+            lfs_file: None,
+            lfs_buffer: Vec::new(),
+            conversion: conversion,
+            converted_size: None,
         })
     }
+
+    /// Get the LFS pointer that the content is, if any, loading it to check the first time
+    /// it's needed: when the size is asked for, or the file is opened.
+    fn pointer(&mut self, repo: &git2::Repository) -> Result<Option<&lfs::Pointer>, libc::c_int> {
+        if self.lfs.is_none() {
+            let blob = try!(trace::git_errno("find_blob", || repo.find_blob(self.oid)));
+            self.lfs = Some(lfs::Pointer::parse(blob.content()));
+        }
+        Ok(self.lfs.as_ref().and_then(|lfs| lfs.as_ref()))
+    }
+
+    /// Get the size of the content to serve, which may come from LFS or a checkout conversion.
+    fn content_size(&mut self, repo: &git2::Repository) -> Result<u64, libc::c_int> {
+        let lfs_policy = self.lfs_policy;
+        if let Some(pointer) = try!(self.pointer(repo)) {
+            match fs::metadata(pointer.path(repo.path())) {
+                Ok(metadata) => return Ok(metadata.len()),
+                Err(_) => if lfs_policy == LfsPolicy::Required {
+                    // Report what the content should be, even though reads will fail
+                    return Ok(pointer.size());
+                },
+            }
+        }
+        if self.conversion.is_some() {
+            // Converted sizes can only be known by converting, so remember them.
+            if self.converted_size.is_none() {
+                let size = match self.data {
+                    Some(ref data) => data.len(),
+                    None => try!(self.load(repo)).len(),
+                };
+                self.converted_size = Some(size as u64);
+            }
+            return Ok(self.converted_size.unwrap());
+        }
+        Ok(self.size)
+    }
+
+    /// Load the content through the shared cache, with any checkout conversion applied.
+    fn load(&self, repo: &git2::Repository) -> Result<Arc<Vec<u8>>, libc::c_int> {
+        let oid = self.oid;
+        let data = try!(self.cache.lock().unwrap().get(Key::Blob(oid), || {
+            let blob = try!(trace::git_errno("find_blob", || repo.find_blob(oid)));
+            Ok(blob.content().to_vec())
+        }));
+        match self.conversion {
+            // NB: Converted content depends on the path, so only the raw blob is shared
+            Some(ref conversion) => conversion.apply(oid, data.to_vec()).map(Arc::new),
+            None => Ok(data),
+        }
+    }
+
+    /// Open the local LFS object, if this is a pointer and the object is available.
+    fn open_lfs(&mut self, repo: &git2::Repository) -> Result<bool, libc::c_int> {
+        if self.lfs_file.is_some() {
+            return Ok(true);
+        }
+        let lfs_policy = self.lfs_policy;
+        let file = match try!(self.pointer(repo)) {
+            Some(pointer) => match fs::File::open(pointer.path(repo.path())) {
+                Ok(file) => file,
+                Err(_) => if lfs_policy == LfsPolicy::Required {
+                    return Err(libc::EIO);
+                } else {
+                    return Ok(false);
+                },
+            },
+            None => return Ok(false),
+        };
+        self.lfs_file = Some(file);
+        Ok(true)
+    }
 }
 
 impl inode::Inode for Blob {
-    fn getattr(&mut self, _repo: &git2::Repository, attr: inode::FileAttr
+    fn getattr(&mut self, repo: &git2::Repository, attr: inode::FileAttr
               ) -> Result<inode::FileAttr, libc::c_int> {
-        Ok(inode::FileAttr {
-            size: self.size,
-            blocks: inode::st_blocks(self.size),
-            kind: FileType::RegularFile,
-            perm: 0o644,
-            ..attr
-        })
+        let size = try!(self.content_size(repo));
+        Ok(getattr(size, attr))
     }
 
     fn open(&mut self, repo: &git2::Repository, _flags: u32) -> Result<u32, libc::c_int> {
-        if self.data.is_none() {
-            if let Ok(blob) = repo.find_blob(self.oid) {
-                self.data = Some(blob.content().to_vec());
-            } else {
-                return Err(libc::EIO)
-            }
+        // LFS content is read from its own file directly, rather than kept in memory
+        if !try!(self.open_lfs(repo)) && self.data.is_none() {
+            self.data = Some(try!(self.load(repo)));
         }
         Ok(fuse::consts::FOPEN_KEEP_CACHE)
     }
 
     fn read(&mut self, _repo: &git2::Repository, offset: u64, size: u32
            ) -> Result<&[u8], libc::c_int> {
+        if let Some(ref mut file) = self.lfs_file {
+            self.lfs_buffer.clear();
+            try!(file.seek(SeekFrom::Start(offset)).map_err(|_| libc::EIO));
+            try!(file.by_ref().take(size as u64).read_to_end(&mut self.lfs_buffer)
+                 .map_err(|_| libc::EIO));
+            return Ok(&self.lfs_buffer);
+        }
         if let Some(ref data) = self.data {
-            if offset <= data.len() as u64 {
-                let data = &data[offset as usize..];
-                return Ok(if (size as usize) < data.len() {
-                    &data[..size as usize]
-                } else {
-                    data
-                })
-            }
+            // Reading at or past the end is simply empty
+            let start = cmp::min(offset, data.len() as u64) as usize;
+            let end = cmp::min(start as u64 + size as u64, data.len() as u64) as usize;
+            return Ok(&data[start..end]);
         }
-        Err(libc::EINVAL)
+        Err(libc::EBADF)
     }
 
     fn release (&mut self, _repo: &git2::Repository) -> Result<(), libc::c_int> {
         self.data.take();
+        self.lfs_file.take();
+        self.lfs_buffer = Vec::new();
         Ok(())
     }
+
+    fn listxattr(&mut self, _repo: &git2::Repository) -> Result<Vec<OsString>, libc::c_int> {
+        Ok(inode::object_listxattr())
+    }
+
+    fn getxattr(&mut self, _repo: &git2::Repository, name: &OsStr
+               ) -> Result<Vec<u8>, libc::c_int> {
+        inode::object_getxattr(self.oid, git2::ObjectType::Blob, name)
+    }
 }
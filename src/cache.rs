@@ -0,0 +1,150 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A cache of blob content shared by every inode, so opening the same content again, under
+//! any name, doesn't have to find and decompress it again.  Generated archives are kept here
+//! too.
+
+use git2;
+use libc;
+use std::collections::{btree_map, hash_map};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use archive::Format;
+
+
+/// A handle to the cache, as held by each blob inode
+pub type SharedCache = Arc<Mutex<BlobCache>>;
+
+
+/// What some cached content is
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum Key {
+    /// The content of a blob
+    Blob(git2::Oid),
+    /// An archive of a revision, as generated within the repository at a GIT_DIR, since it
+    /// leaves out whatever that repository hides
+    Archive(PathBuf, git2::Oid, Format),
+}
+
+
+struct Entry {
+    data: Arc<Vec<u8>>,
+    used: u64,
+}
+
+/// Blob content by oid, bounded to a total size by evicting the least recently used
+///
+/// Content stays alive as long as any open file holds it, even once it's evicted here, but
+/// only what the cache itself holds counts toward the limit.
+pub struct BlobCache {
+    limit: usize,
+    size: usize,
+    clock: u64,
+    entries: hash_map::HashMap<Key, Entry>,
+    /// The keys of all entries, by when they were last used
+    order: btree_map::BTreeMap<u64, Key>,
+    /// The size and time of each archive generated so far, kept even once its content isn't,
+    /// so it's generated the same way again
+    archives: hash_map::HashMap<Key, (u64, i64)>,
+    hits: u64,
+    misses: u64,
+}
+
+impl BlobCache {
+    pub fn new(limit: usize) -> BlobCache {
+        BlobCache {
+            limit: limit,
+            size: 0,
+            clock: 0,
+            entries: hash_map::HashMap::new(),
+            order: btree_map::BTreeMap::new(),
+            archives: hash_map::HashMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn shared(limit: usize) -> SharedCache {
+        Arc::new(Mutex::new(BlobCache::new(limit)))
+    }
+
+    /// Change the limit on the total size of content, evicting whatever no longer fits.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.evict(0);
+    }
+
+    /// Get some content, calling `load` to get it on a miss.  Anything larger than the whole
+    /// cache is passed through without being kept.
+    pub fn get<F>(&mut self, key: Key, load: F) -> Result<Arc<Vec<u8>>, libc::c_int>
+        where F: FnOnce() -> Result<Vec<u8>, libc::c_int>
+    {
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(&key) {
+            self.hits += 1;
+            self.order.remove(&entry.used);
+            self.order.insert(self.clock, key);
+            entry.used = self.clock;
+            return Ok(entry.data.clone());
+        }
+
+        self.misses += 1;
+        let data = Arc::new(try!(load()));
+        if data.len() <= self.limit {
+            self.evict(data.len());
+            self.size += data.len();
+            self.order.insert(self.clock, key.clone());
+            self.entries.insert(key, Entry { data: data.clone(), used: self.clock });
+        }
+        Ok(data)
+    }
+
+    /// Evict the least recently used content until `extra` more bytes would fit.
+    fn evict(&mut self, extra: usize) {
+        while self.size + extra > self.limit {
+            let (used, key) = match self.order.iter().next() {
+                Some((&used, key)) => (used, key.clone()),
+                None => break,
+            };
+            self.order.remove(&used);
+            if let Some(entry) = self.entries.remove(&key) {
+                self.size -= entry.data.len();
+            }
+        }
+    }
+
+    /// Get the size and time of an archive that has been generated before, if it has.
+    pub fn archive(&self, key: &Key) -> Option<(u64, i64)> {
+        self.archives.get(key).cloned()
+    }
+
+    /// Remember the size and time of an archive once it's generated.
+    pub fn set_archive(&mut self, key: Key, size: u64, mtime: i64) {
+        self.archives.insert(key, (size, mtime));
+    }
+
+    /// Drop all content, keeping the counters, and the sizes of archives.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.size = 0;
+    }
+
+    /// List the counters and sizes of the cache by name, for `stats`.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("blob_cache_hits", self.hits),
+            ("blob_cache_misses", self.misses),
+            ("blob_cache_entries", self.entries.len() as u64),
+            ("blob_cache_bytes", self.size as u64),
+            ("blob_cache_limit", self.limit as u64),
+        ]
+    }
+}
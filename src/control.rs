@@ -0,0 +1,196 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The hidden `.git-fs/` directory, for inspecting and steering a running filesystem.
+
+use fuse::FileType;
+use git2;
+use libc;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use inode;
+use inode::{FileAttr, Id, Inode};
+
+
+/// The name of the control directory, found at the root but not listed there
+pub const DIR_NAME: &'static str = ".git-fs";
+
+
+/// The files within the control directory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum File {
+    /// Counters of requests, inodes and bytes served
+    Stats,
+    /// The effective options
+    Config,
+    /// The version of git-fs
+    Version,
+    /// Write anything to reread all references
+    Refresh,
+    /// Write anything to drop cached inodes and content
+    DropCaches,
+}
+
+/// All of the control files, in listing order
+pub const FILES: [File; 5] = [File::Stats, File::Config, File::Version,
+                              File::Refresh, File::DropCaches];
+
+impl File {
+    /// The name of this file in the control directory
+    pub fn name(self) -> &'static str {
+        match self {
+            File::Stats => "stats",
+            File::Config => "config",
+            File::Version => "version",
+            File::Refresh => "refresh",
+            File::DropCaches => "drop-caches",
+        }
+    }
+
+    /// Whether this file is written to ask for a command, rather than read
+    pub fn is_command(self) -> bool {
+        match self {
+            File::Refresh | File::DropCaches => true,
+            _ => false,
+        }
+    }
+
+    /// Fill in the attributes of this file.
+    pub fn getattr(self, attr: FileAttr) -> FileAttr {
+        FileAttr {
+            kind: FileType::RegularFile,
+            perm: if self.is_command() { 0o200 } else { 0o444 },
+            ..attr
+        }
+    }
+}
+
+
+/// Commands asked for by writing to a control file, or from other threads like the one
+/// handling signals, which are carried out before the next request.
+#[derive(Default)]
+pub struct Commands {
+    refresh: AtomicBool,
+    drop_caches: AtomicBool,
+}
+
+impl Commands {
+    /// Ask for all references to be reread.
+    pub fn refresh(&self) {
+        self.refresh.store(true, Ordering::SeqCst);
+    }
+
+    /// Ask for every inode that isn't open, and all cached blob content, to be dropped.
+    pub fn drop_caches(&self) {
+        self.drop_caches.store(true, Ordering::SeqCst);
+    }
+
+    /// Take the pending commands, as whether to `(refresh, drop_caches)`.
+    pub fn take(&self) -> (bool, bool) {
+        (self.refresh.swap(false, Ordering::SeqCst),
+         self.drop_caches.swap(false, Ordering::SeqCst))
+    }
+}
+
+
+/// Counters of what the filesystem has done
+#[derive(Default)]
+pub struct Stats {
+    pub lookups: u64,
+    pub getattrs: u64,
+    pub opens: u64,
+    pub reads: u64,
+    pub readdirs: u64,
+    pub xattrs: u64,
+    pub bytes_read: u64,
+    pub inode_hits: u64,
+    pub inode_misses: u64,
+    pub refreshes: u64,
+    pub cache_drops: u64,
+}
+
+impl Stats {
+    /// List the counters by name.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("lookups", self.lookups),
+            ("getattrs", self.getattrs),
+            ("opens", self.opens),
+            ("reads", self.reads),
+            ("readdirs", self.readdirs),
+            ("xattrs", self.xattrs),
+            ("bytes_read", self.bytes_read),
+            ("inode_hits", self.inode_hits),
+            ("inode_misses", self.inode_misses),
+            ("refreshes", self.refreshes),
+            ("cache_drops", self.cache_drops),
+        ]
+    }
+}
+
+
+/// Format `key value` lines, like the contents of `stats` and `config`.
+pub fn format_lines<V: ToString>(lines: &[(&str, V)]) -> Vec<u8> {
+    let mut text = String::new();
+    for &(key, ref value) in lines {
+        text.push_str(key);
+        text.push(' ');
+        text.push_str(&value.to_string());
+        text.push('\n');
+    }
+    text.into_bytes()
+}
+
+
+/// The control directory itself, listing each control file
+pub struct ControlDir {
+    files: Vec<(File, u64)>,
+}
+
+impl ControlDir {
+    pub fn new(files: Vec<(File, u64)>) -> Box<Inode+'static> {
+        Box::new(ControlDir {
+            files: files,
+        })
+    }
+}
+
+impl Inode for ControlDir {
+    fn lookup(&mut self, _repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        self.files.iter()
+            .find(|&&(file, _)| name == Path::new(file.name()))
+            .map(|&(_, ino)| Id::Ino(ino))
+            .ok_or(libc::ENOENT)
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = self.files.len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, _repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for &(file, ino) in self.files.iter().skip(offset as usize) {
+            if add(Id::Ino(ino), FileType::RegularFile, Path::new(file.name())) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for running a mount in the background and tearing it down on signals.
+
+use libc;
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use std::sync::Arc;
+use std::thread;
+
+use control::Commands;
+
+
+/// Detach from the terminal: fork, and start a new session in the child.
+///
+/// The parent waits until the child reports whether it started with `Ready::report`,
+/// so its errors can still be seen, and then exits with `_exit` so that none of its
+/// destructors run, since the child now owns everything, including the mount itself.
+pub fn detach() -> io::Result<Ready> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (reader, writer) = unsafe {
+        (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1]))
+    };
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => drop(reader),
+        _ => {
+            drop(writer);
+            wait_ready(reader)
+        },
+    }
+
+    let ready = Ready { pipe: writer };
+    if let Err(e) = new_session() {
+        return ready.report(Err(e));
+    }
+    Ok(ready)
+}
+
+/// Wait in the parent for the child's report, then exit with its status.
+fn wait_ready(mut reader: fs::File) -> ! {
+    let mut report = Vec::new();
+    let message = match reader.read_to_end(&mut report) {
+        Ok(_) if report == b"\0" => unsafe { libc::_exit(0) },
+        Ok(_) if report.is_empty() => "the daemon exited before it was ready".to_string(),
+        Ok(_) => String::from_utf8_lossy(&report).into_owned(),
+        Err(e) => e.to_string(),
+    };
+    writeln!(io::stderr(), "git-fs: {}", message).ok();
+    unsafe { libc::_exit(1) }
+}
+
+/// Start a new session for the child, away from the caller's directory and terminal.
+fn new_session() -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Don't keep the caller's working directory busy
+    try!(::std::env::set_current_dir("/"));
+
+    // Point stdio at /dev/null, since there's no terminal to report to anymore
+    let null = CString::new("/dev/null").unwrap();
+    let fd = unsafe { libc::open(null.as_ptr(), libc::O_RDWR) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for target in 0..3 {
+        if unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if fd > 2 {
+        unsafe { libc::close(fd) };
+    }
+
+    Ok(())
+}
+
+
+/// The child's end of `detach`, for telling the waiting parent whether it started.
+pub struct Ready {
+    pipe: fs::File,
+}
+
+impl Ready {
+    /// Report how starting up went, letting the parent exit, and pass the result along.
+    pub fn report<T>(mut self, result: io::Result<T>) -> io::Result<T> {
+        let message = match result {
+            Ok(_) => vec![0],
+            Err(ref e) => e.to_string().into_bytes(),
+        };
+        // NB: There's nowhere left to report a failure to report
+        self.pipe.write_all(&message).ok();
+        result
+    }
+}
+
+
+/// Helper for a PID file, ensuring removal when dropped
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<PidFile> {
+        let mut file = try!(fs::File::create(path));
+        try!(writeln!(file, "{}", unsafe { libc::getpid() }));
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+
+/// Block SIGINT, SIGTERM, SIGHUP and SIGUSR1 in this thread, and spawn a watcher thread to
+/// unmount `mountpoint` on SIGINT or SIGTERM.  SIGHUP asks `commands` for a refresh, and
+/// SIGUSR1 for caches to be dropped.
+///
+/// This must be called before any other threads are started, so they all
+/// inherit the blocked mask and the signals are only seen by `sigwait`.
+/// The session loop then sees the unmount and returns normally, which lets
+/// all of the usual cleanup run.
+pub fn handle_signals(mountpoint: &Path, commands: Arc<Commands>) -> io::Result<()> {
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        set
+    };
+
+    let rc = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if rc != 0 {
+        return Err(io::Error::from_raw_os_error(rc));
+    }
+
+    let mountpoint = mountpoint.to_path_buf();
+    try!(thread::Builder::new().name("git-fs signals".to_string()).spawn(move || {
+        loop {
+            let mut sig = 0;
+            if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                continue;
+            }
+            match sig {
+                // NB: These are carried out by the session itself, before its next request
+                libc::SIGHUP => commands.refresh(),
+                libc::SIGUSR1 => commands.drop_caches(),
+                _ => if unmount(&mountpoint) {
+                    break;
+                },
+            }
+        }
+    }));
+
+    Ok(())
+}
+
+
+/// Ask the system to unmount a FUSE path, returning whether it succeeded.
+fn unmount(mountpoint: &Path) -> bool {
+    let status = Command::new("fusermount").arg("-u").arg(mountpoint).status()
+        .or_else(|_| Command::new("umount").arg(mountpoint).status());
+    match status {
+        Ok(status) => status.success(),
+        Err(_) => false,
+    }
+}
+
+
+/// Make a path absolute, so it stays valid after `detach` changes directory.
+pub fn absolute(path: &Path) -> io::Result<PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        ::std::env::current_dir().map(|cwd| cwd.join(path))
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Filters on which paths of a tree are visible, from `--include` and `--exclude` patterns
+//! and the repository's sparse-checkout patterns.
+
+use git2;
+use std::fs;
+use std::io::Read;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use Options;
+use glob;
+
+
+/// One pattern, with the meaning it has in a `.gitignore` file
+#[derive(Debug)]
+struct Pattern {
+    glob: Vec<u8>,
+    /// Whether this is a `!` pattern, taking back what earlier patterns matched
+    negate: bool,
+    /// Whether this only matches directories, i.e. it had a trailing `/`
+    dir_only: bool,
+    /// Whether this matches the whole path, rather than only the final name at any depth
+    anchored: bool,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Pattern {
+        let mut glob = pattern.as_bytes();
+        let dir_only = glob.len() > 1 && glob.last() == Some(&b'/');
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        // Patterns with a slash are anchored to the root of the tree
+        let anchored = glob.contains(&b'/');
+        if glob.first() == Some(&b'/') {
+            glob = &glob[1..];
+        }
+        Pattern {
+            glob: glob.to_vec(),
+            negate: false,
+            dir_only: dir_only,
+            anchored: anchored,
+        }
+    }
+
+    /// Parse one line of a sparse-checkout file, which may be blank or a comment.
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim_right();
+        if line.is_empty() || line.starts_with('#') {
+            None
+        } else if line.starts_with('!') {
+            Some(Pattern { negate: true, ..Pattern::new(&line[1..]) })
+        } else {
+            // A backslash escapes a leading `!` or `#`
+            let line = if line.starts_with('\\') { &line[1..] } else { line };
+            Some(Pattern::new(line))
+        }
+    }
+
+    /// Check whether this matches `path` itself, which is a directory if `is_dir`.
+    fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            false
+        } else if self.anchored {
+            glob::matches(&self.glob, path)
+        } else {
+            let name = match path.iter().rposition(|&c| c == b'/') {
+                Some(i) => &path[i + 1..],
+                None => path,
+            };
+            glob::matches(&self.glob, name)
+        }
+    }
+
+    /// Check whether this matches `path` or any directory containing it.
+    fn covers(&self, path: &[u8], is_dir: bool) -> bool {
+        self.matches(path, is_dir) || parents(path).any(|parent| self.matches(parent, true))
+    }
+
+    /// Check whether this could match something within the directory `dir`, which it
+    /// doesn't match itself.
+    fn within(&self, dir: &[u8]) -> bool {
+        if !self.anchored {
+            return true;
+        }
+        let mut globs = self.glob.split(|&c| c == b'/');
+        for name in dir.split(|&c| c == b'/') {
+            match globs.next() {
+                // NB: `**` may match any number of directories, so just assume it does
+                Some(glob) if glob.windows(2).any(|w| w == b"**") => return true,
+                Some(glob) if glob::matches(glob, name) => (),
+                _ => return false,
+            }
+        }
+        globs.next().is_some()
+    }
+}
+
+
+/// Iterate over the directories containing `path`, innermost first.
+fn parents<'a>(path: &'a [u8]) -> Box<Iterator<Item=&'a [u8]> + 'a> {
+    Box::new(path.iter().enumerate().rev()
+             .filter(|&(_, &c)| c == b'/')
+             .map(move |(i, _)| &path[..i]))
+}
+
+
+/// Which paths of a tree are visible
+#[derive(Debug)]
+pub struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    sparse: Option<Vec<Pattern>>,
+}
+
+impl Filter {
+    /// Create the filter a repository's trees should have with the given options, or `None`
+    /// if everything is visible.
+    pub fn new(repo: &git2::Repository, options: &Options) -> Option<Filter> {
+        let sparse = if options.sparse { read_sparse(repo) } else { None };
+        if options.include.is_empty() && options.exclude.is_empty() && sparse.is_none() {
+            return None;
+        }
+        Some(Filter {
+            include: options.include.iter().map(|pattern| Pattern::new(pattern)).collect(),
+            exclude: options.exclude.iter().map(|pattern| Pattern::new(pattern)).collect(),
+            sparse: sparse,
+        })
+    }
+
+    /// Check whether `path`, relative to the root tree, is visible.  Directories are visible
+    /// if they might contain anything visible.
+    pub fn allows(&self, path: &Path, is_dir: bool) -> bool {
+        let path = path.as_os_str().as_bytes();
+        if self.exclude.iter().any(|p| p.covers(path, is_dir)) {
+            return false;
+        }
+        if !self.include.is_empty() &&
+           !self.include.iter().any(|p| p.covers(path, is_dir) || (is_dir && p.within(path))) {
+            return false;
+        }
+        match self.sparse {
+            Some(ref sparse) => {
+                sparse_state(sparse, path, is_dir) ||
+                    (is_dir && sparse.iter().any(|p| !p.negate && p.within(path)))
+            },
+            None => true,
+        }
+    }
+}
+
+
+/// Check whether sparse-checkout patterns include `path`, like git does: the last pattern
+/// that matches it decides, otherwise it's the same as its directory.
+fn sparse_state(patterns: &[Pattern], path: &[u8], is_dir: bool) -> bool {
+    match patterns.iter().rev().find(|p| p.matches(path, is_dir)) {
+        Some(pattern) => !pattern.negate,
+        None => match parents(path).next() {
+            Some(parent) => sparse_state(patterns, parent, true),
+            None => false,
+        },
+    }
+}
+
+/// Read the patterns of `GIT_DIR/info/sparse-checkout`, if there is such a file.
+fn read_sparse(repo: &git2::Repository) -> Option<Vec<Pattern>> {
+    let path = repo.path().join("info").join("sparse-checkout");
+    let mut content = String::new();
+    match fs::File::open(&path).and_then(|mut file| file.read_to_string(&mut content)) {
+        Ok(_) => Some(content.lines().filter_map(Pattern::parse).collect()),
+        Err(e) => {
+            info!("{}: {}, showing everything", path.display(), e);
+            None
+        },
+    }
+}
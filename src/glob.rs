@@ -0,0 +1,96 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Wildcard matching in the style of gitignore and gitattributes patterns.
+
+
+/// Match `text` against a wildcard `pattern`.
+///
+/// `*` and `?` never match a `/`, while `**` matches across directories, and
+/// a leading `**/` may match nothing at all.  Bracket classes like `[a-z]` or
+/// `[!0-9]` and backslash escapes are also supported.
+pub fn matches(pattern: &[u8], text: &[u8]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+
+    match pattern[0] {
+        b'*' if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&b'/') && matches(&rest[1..], text) {
+                return true;
+            }
+            (0..text.len() + 1).any(|i| matches(rest, &text[i..]))
+        },
+        b'*' => {
+            let rest = &pattern[1..];
+            for i in 0..text.len() + 1 {
+                if matches(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        },
+        b'?' => {
+            !text.is_empty() && text[0] != b'/' && matches(&pattern[1..], &text[1..])
+        },
+        b'[' => {
+            match bracket(&pattern[1..], text.first().cloned()) {
+                Some((true, len)) => matches(&pattern[1 + len..], &text[1..]),
+                Some((false, _)) => false,
+                // An unterminated class is just a literal '['
+                None => text.first() == Some(&b'[') && matches(&pattern[1..], &text[1..]),
+            }
+        },
+        b'\\' if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && matches(&pattern[2..], &text[1..])
+        },
+        c => text.first() == Some(&c) && matches(&pattern[1..], &text[1..]),
+    }
+}
+
+
+/// Match a character against the bracket class at the start of `pattern`, just after the `[`.
+/// Returns whether it matched and the length of the class including `]`, or `None` if the class
+/// is unterminated.
+fn bracket(pattern: &[u8], c: Option<u8>) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negate = match pattern.first() {
+        Some(&b'!') | Some(&b'^') => { i += 1; true },
+        _ => false,
+    };
+
+    let mut found = false;
+    let mut first = true;
+    while i < pattern.len() {
+        let lo = pattern[i];
+        if lo == b']' && !first {
+            let matched = match c {
+                Some(c) if c != b'/' => found != negate,
+                _ => false,
+            };
+            return Some((matched, i + 1));
+        }
+        first = false;
+
+        if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let hi = pattern[i + 2];
+            if let Some(c) = c {
+                found |= lo <= c && c <= hi;
+            }
+            i += 3;
+        } else {
+            found |= c == Some(lo);
+            i += 1;
+        }
+    }
+    None
+}
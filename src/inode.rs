@@ -6,27 +6,54 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use fuse::FileType;
+use fuse::{self, FileType};
 use git2;
 use libc;
-use std::collections::hash_map;
-use std::path::Path;
+use std::collections::{hash_map, hash_set};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use LfsPolicy;
+use Options;
+use archive;
+use attr;
 use blob;
+use cache::SharedCache;
+use filter::Filter;
+use missing;
+use trace;
 use tree;
 
 pub use fuse::FileAttr;
 
 
 /// Let Inodes use either existing inos or git2::Oid, whichever is convenient
-// FIXME Using a 1:1 mapping between oid:ino breaks down when it comes to attributes.  For
-// instance, Blobs and Trees have no concept of their own timestamps or permissions.  But a Tree
-// does know its children's permissions in TreeEntry, and a Commit could propagate timestamps
-// recursively down its Tree.  This will require Oids to be context sensitive, with 1:N inos.
-#[derive(Clone,Copy)]
+///
+/// Oids are mapped to a separate ino for each location they're found, see `InodeMapper`.
+#[derive(Clone,Copy,Hash,PartialEq,Eq)]
 pub enum Id {
     Ino(u64),
     Oid(git2::Oid),
+    /// A virtual archive of a commit or tree
+    Archive(git2::Oid, archive::Format),
+    /// A directory merged from several layers of an overlay, by the oid of its topmost tree
+    Overlay(git2::Oid),
+    /// The result of merging two commits, ours and theirs
+    Merge(git2::Oid, git2::Oid),
+}
+
+
+/// Where an object was reached within a tree, for inodes whose content depends on it
+#[derive(Clone)]
+pub struct Location {
+    /// The root tree that was being browsed
+    pub root: git2::Oid,
+    /// The path of the object within that root
+    pub path: PathBuf,
+    /// The commit whose tree is the root, if it was reached through one
+    pub commit: Option<git2::Oid>,
 }
 
 
@@ -60,54 +87,257 @@ pub trait Inode: Send {
         Err(libc::EISDIR)
     }
 
+    /// List the names of this Inode's extended attributes.
+    fn listxattr(&mut self, _repo: &git2::Repository) -> Result<Vec<OsString>, libc::c_int> {
+        Ok(Vec::new())
+    }
+
+    /// Get the value of one of this Inode's extended attributes.
+    fn getxattr(&mut self, _repo: &git2::Repository, _name: &OsStr
+               ) -> Result<Vec<u8>, libc::c_int> {
+        Err(libc::ENODATA)
+    }
+
     /// Read directory entries from this Inode.
     fn readdir<'a>(&'a mut self, _repo: &git2::Repository, _offset: u64,
                _add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
               ) -> Result<(), libc::c_int> {
         Err(libc::ENOTDIR)
     }
+
+    /// Whether this only stands in for an object that's missing, which may turn up later.
+    fn is_placeholder(&self) -> bool {
+        false
+    }
 }
 
 
-/// Assign new inode numbers, and map Oids to ino dynamically
-// FIXME see the note on Id about 1:1 mapping trouble
+/// Assign inode numbers, and map Oids (and other Ids derived from them) to ino dynamically
+///
+/// Each repository being served gets its own namespace of Oids, identified by its index in the
+/// GitFS, but inode numbers are unique across all of them.  Objects get their own inode for
+/// each place they're found, so every inode has a single parent, except for blobs shared as
+/// hard links.
+///
+/// Inode numbers are hashed from what they represent and where, so they're the same on every
+/// mount regardless of the order things are found.  In the rare case of a collision, the next
+/// free number is used instead, which may then depend on that order.
 #[derive(Default)]
 pub struct InodeMapper {
-    max_ino: u64,
-    oids: hash_map::HashMap<git2::Oid, u64>,
-    inos: hash_map::HashMap<u64, git2::Oid>,
+    seeds: hash_map::HashMap<usize, u64>,
+    paths: hash_map::HashMap<(usize, PathBuf), u64>,
+    ids: hash_map::HashMap<(u64, PathBuf, Id), u64>,
+    shared: hash_map::HashMap<(usize, git2::Oid, Id), u64>,
+    inos: hash_map::HashMap<u64, Id>,
+    namespaces: hash_map::HashMap<u64, usize>,
+    parents: hash_map::HashMap<u64, u64>,
 }
 
 impl InodeMapper {
-    /// Reserve a new inode number
-    pub fn new_ino(&mut self) -> u64 {
-        self.max_ino += 1;
-        self.max_ino
+    /// Name a namespace, e.g. by its directory in a scan, to keep it distinct from others
+    pub fn set_namespace(&mut self, ns: usize, name: &[u8]) {
+        self.seeds.insert(ns, fnv(&[b"namespace", name]));
+    }
+
+    fn seed(&self, ns: usize) -> [u8; 8] {
+        u64_bytes(self.seeds.get(&ns).cloned().unwrap_or(0))
+    }
+
+    /// Take the next free inode number from `hash`, for the given namespace
+    fn assign(&mut self, ns: usize, hash: u64) -> u64 {
+        let mut ino = hash;
+        // Keep clear of 0, which is invalid, and the root
+        while ino <= fuse::FUSE_ROOT_ID || self.namespaces.contains_key(&ino) {
+            ino = ino.wrapping_add(1);
+        }
+        self.namespaces.insert(ino, ns);
+        ino
+    }
+
+    /// Reserve the root inode number, in the first namespace
+    pub fn root_ino(&mut self) -> u64 {
+        self.namespaces.insert(fuse::FUSE_ROOT_ID, 0);
+        self.parents.insert(fuse::FUSE_ROOT_ID, fuse::FUSE_ROOT_ID);
+        fuse::FUSE_ROOT_ID
     }
 
-    /// Get the oid associated with this ino
-    pub fn get_oid(&self, ino: u64) -> Option<git2::Oid> {
+    /// Reserve an inode number in the given namespace, for the fixed directory at `path`.
+    /// Reserving the same path again gives the same number.
+    pub fn new_ino(&mut self, ns: usize, path: &Path) -> u64 {
+        let key = (ns, path.to_path_buf());
+        if let Some(&ino) = self.paths.get(&key) {
+            return ino;
+        }
+        let hash = fnv(&[&self.seed(ns), b"path", path.as_os_str().as_bytes()]);
+        let ino = self.assign(ns, hash);
+        self.paths.insert(key, ino);
+        ino
+    }
+
+    /// Get the Id that was mapped to this ino, if it wasn't reserved directly
+    pub fn get_id(&self, ino: u64) -> Option<Id> {
         self.inos.get(&ino).cloned()
     }
 
-    /// Map any Id to an inode number
-    pub fn get_ino(&mut self, id: Id) -> u64 {
-        match id {
-            Id::Ino(ino) => ino,
-            Id::Oid(oid) => {
-                match self.oids.entry(oid) {
-                    hash_map::Entry::Occupied(entry) => *entry.get(),
-                    hash_map::Entry::Vacant(entry) => {
-                        // NB can't call new_ino because entry holds mut
-                        self.max_ino += 1;
-                        let ino = self.max_ino;
-                        self.inos.insert(ino, oid);
-                        *entry.insert(ino)
-                    },
-                }
-            },
+    /// Get the number of inode numbers assigned so far
+    pub fn count(&self) -> u64 {
+        self.namespaces.len() as u64
+    }
+
+    /// Get the namespace that this ino belongs to
+    pub fn namespace(&self, ino: u64) -> Option<usize> {
+        self.namespaces.get(&ino).cloned()
+    }
+
+    /// Get the directory that this ino was found in, if it has been found yet
+    pub fn parent(&self, ino: u64) -> Option<u64> {
+        self.parents.get(&ino).cloned()
+    }
+
+    /// Map any Id found as entry `name` of directory `parent` to an inode number, with Oids
+    /// interpreted in the given namespace.
+    pub fn get_ino(&mut self, ns: usize, id: Id, parent: u64, name: &Path) -> u64 {
+        if let Id::Ino(ino) = id {
+            self.parents.entry(ino).or_insert(parent);
+            return ino;
+        }
+
+        let key = (parent, name.to_path_buf(), id);
+        if let Some(&ino) = self.ids.get(&key) {
+            return ino;
+        }
+        let hash = fnv(&[&u64_bytes(parent), name.as_os_str().as_bytes(), &id_bytes(id)]);
+        let ino = self.assign(ns, hash);
+        self.inos.insert(ino, id);
+        self.parents.insert(ino, parent);
+        self.ids.insert(key, ino);
+        ino
+    }
+
+    /// Find the inode number that an Id found as entry `name` of directory `parent` was
+    /// mapped to, if it has been.
+    pub fn find_ino(&self, id: Id, parent: u64, name: &Path) -> Option<u64> {
+        self.ids.get(&(parent, name.to_path_buf(), id)).cloned()
+    }
+
+    /// List the inode numbers found in a directory so far.
+    pub fn children(&self, parent: u64) -> Vec<u64> {
+        self.parents.iter()
+            .filter(|&(&ino, &found)| found == parent && ino != parent)
+            .map(|(&ino, _)| ino)
+            .collect()
+    }
+
+    /// List an inode number and every one found within it so far, recursively, except blobs
+    /// shared as hard links, which may be found elsewhere too.
+    pub fn descendants(&self, ino: u64) -> Vec<u64> {
+        let shared: hash_set::HashSet<u64> = self.shared.values().cloned().collect();
+        let mut children = hash_map::HashMap::new();
+        for (&child, &parent) in &self.parents {
+            if child != parent && !shared.contains(&child) {
+                children.entry(parent).or_insert_with(Vec::new).push(child);
+            }
+        }
+
+        let mut found = vec![ino];
+        let mut i = 0;
+        while i < found.len() {
+            if let Some(more) = children.remove(&found[i]) {
+                found.extend(more);
+            }
+            i += 1;
+        }
+        found
+    }
+
+    /// Forget inode numbers that are gone, like the directory of deleted references, so they
+    /// no longer map to anything.  Finding the same thing again usually gives the same number.
+    pub fn forget(&mut self, gone: &hash_set::HashSet<u64>) {
+        let paths: Vec<_> = self.paths.iter()
+            .filter(|&(_, ino)| gone.contains(ino))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in paths {
+            self.paths.remove(&key);
         }
+        let ids: Vec<_> = self.ids.iter()
+            .filter(|&(_, ino)| gone.contains(ino))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in ids {
+            self.ids.remove(&key);
+        }
+        for ino in gone {
+            self.inos.remove(ino);
+            self.namespaces.remove(ino);
+            self.parents.remove(ino);
+        }
+    }
+
+    /// Map an Id to a single inode number for everywhere it's found within the tree `root`,
+    /// with Oids interpreted in the given namespace.  The first `parent` it's found in is
+    /// recorded as its parent.
+    pub fn get_shared_ino(&mut self, ns: usize, id: Id, root: git2::Oid, parent: u64) -> u64 {
+        let key = (ns, root, id);
+        if let Some(&ino) = self.shared.get(&key) {
+            return ino;
+        }
+        let hash = fnv(&[&self.seed(ns), b"shared", root.as_bytes(), &id_bytes(id)]);
+        let ino = self.assign(ns, hash);
+        self.inos.insert(ino, id);
+        self.parents.insert(ino, parent);
+        self.shared.insert(key, ino);
+        ino
+    }
+}
+
+
+/// Hash a sequence of byte strings with 64-bit FNV-1a, which unlike the std hashers is
+/// guaranteed to give the same results with every build.
+fn fnv(parts: &[&[u8]]) -> u64 {
+    const PRIME: u64 = 0x100000001b3;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for &b in *part {
+            hash ^= b as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+        // Mark the end of each part, so ("ab", "c") and ("a", "bc") differ
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(PRIME);
     }
+    hash
+}
+
+fn u64_bytes(n: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (n >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+/// Get bytes that identify an Id for hashing.
+fn id_bytes(id: Id) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    match id {
+        Id::Ino(ino) => bytes.extend_from_slice(&u64_bytes(ino)),
+        Id::Oid(oid) => bytes.extend_from_slice(oid.as_bytes()),
+        Id::Archive(oid, format) => {
+            bytes.extend_from_slice(oid.as_bytes());
+            bytes.extend_from_slice(format.suffix().as_bytes());
+        },
+        Id::Overlay(oid) => {
+            bytes.extend_from_slice(oid.as_bytes());
+            bytes.extend_from_slice(b"overlay");
+        },
+        Id::Merge(ours, theirs) => {
+            bytes.extend_from_slice(ours.as_bytes());
+            bytes.extend_from_slice(theirs.as_bytes());
+            bytes.extend_from_slice(b"merge");
+        },
+    }
+    bytes
 }
 
 
@@ -131,26 +361,116 @@ impl InodeContainer {
     -> hash_map::Entry<u64, Box<Inode+'static>> {
         self.inodes.entry(ino)
     }
+
+    pub fn contains(&self, ino: u64) -> bool {
+        self.inodes.contains_key(&ino)
+    }
+
+    pub fn remove(&mut self, ino: u64) -> Option<Box<Inode+'static>> {
+        self.inodes.remove(&ino)
+    }
+
+    /// List the inos currently holding an Inode
+    pub fn inos(&self) -> Vec<u64> {
+        self.inodes.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
 }
 
 
-/// Creates an Inode from any Oid.
-// FIXME see the note on Id about 1:1 mapping trouble
-pub fn new_inode(repo: &git2::Repository, oid: git2::Oid) -> Option<Box<Inode+'static>> {
-    match repo.find_object(oid, None).ok().and_then(|o| o.kind()) {
-        Some(git2::ObjectType::Blob) => {
-            repo.find_blob(oid).ok().map(|blob| blob::Blob::new(blob))
+/// Creates an Inode from any Id other than a plain ino, an overlay or a merge, found at
+/// `location` if known.  Blobs share their content through `cache`, and find their checkout
+/// conversions with `rules`, while trees and archives leave out whatever `filter` hides.
+pub fn new_inode(repo: &git2::Repository, id: Id, options: &Options,
+                 location: Option<&Location>, cache: &SharedCache, rules: &mut attr::RulesCache,
+                 filter: Option<&Arc<Filter>>) -> Result<Box<Inode+'static>, libc::c_int> {
+    let oid = match id {
+        Id::Ino(_) | Id::Overlay(_) | Id::Merge(..) => return Err(libc::ENOENT),
+        Id::Oid(oid) => oid,
+        Id::Archive(oid, format) => {
+            return Ok(archive::Archive::new(repo, oid, format, filter.cloned(), cache.clone()));
+        },
+    };
+    // NB: Only the header is read here, so blobs aren't loaded until they're opened
+    let odb = try!(trace::git_errno("odb", || repo.odb()));
+    let (size, kind) = match trace::git("read_header", || odb.read_header(oid)) {
+        Ok(header) => header,
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+            // Objects may be missing on purpose, from a partial or shallow clone
+            if let Some(ref promisor) = options.promisor {
+                if missing::fetch(repo, promisor, oid) {
+                    return new_inode(repo, id, options, location, cache, rules, filter);
+                }
+            }
+            return match missing::reason(repo) {
+                Some(reason) => missing::Missing::new(repo, oid, reason, location),
+                None => Err(trace::errno("read_header", e)),
+            };
+        },
+        Err(e) => return Err(trace::errno("read_header", &e)),
+    };
+    match kind {
+        git2::ObjectType::Blob => {
+            let conversion = match location {
+                Some(location) if options.checkout => {
+                    // LFS pointers are already resolved, without the external filter
+                    let skip = match options.lfs {
+                        LfsPolicy::Disabled => &[][..],
+                        _ => &["lfs"][..],
+                    };
+                    attr::Conversion::new(repo, location.root, &location.path, skip,
+                                          options.filter_timeout, rules)
+                },
+                _ => None,
+            };
+            Ok(blob::Blob::new(oid, size as u64, options.lfs, conversion, cache.clone()))
         },
-        Some(git2::ObjectType::Tree) => {
-            repo.find_tree(oid).ok().map(|tree| tree::Tree::new(tree))
+        git2::ObjectType::Tree => {
+            let tree = try!(trace::git_errno("find_tree", || repo.find_tree(oid)));
+            let path = location.map_or(Path::new(""), |location| &location.path);
+            Ok(tree::Tree::new(tree, path, filter))
         },
-        Some(git2::ObjectType::Commit) => {
+        git2::ObjectType::Commit => {
             // FIXME a first-class Commit might expose things like the message as xattrs,
             // but for now just redirect straight to the tree id.
-            repo.find_commit(oid).ok()
-                .and_then(|commit| new_inode(repo, commit.tree_id()))
+            let commit = try!(trace::git_errno("find_commit", || repo.find_commit(oid)));
+            new_inode(repo, Id::Oid(commit.tree_id()), options, location, cache, rules, filter)
         },
-        _ => None,
+        _ => Err(libc::ENOENT),
+    }
+}
+
+
+/// Find the tree that an object represents, if any, like the root tree of a commit.
+pub fn peel_tree(repo: &git2::Repository, oid: git2::Oid) -> Option<git2::Oid> {
+    repo.find_object(oid, None).ok()
+        .and_then(|object| object.peel(git2::ObjectType::Tree).ok())
+        .map(|tree| tree.id())
+}
+
+
+/// The extended attribute of a Git object's id
+pub const XATTR_OID: &'static str = "user.git.oid";
+/// The extended attribute of a Git object's type, like "blob" or "tree"
+pub const XATTR_TYPE: &'static str = "user.git.type";
+
+/// List the extended attributes of a Git object.
+pub fn object_listxattr() -> Vec<OsString> {
+    vec![OsString::from(XATTR_OID), OsString::from(XATTR_TYPE)]
+}
+
+/// Get an extended attribute of a Git object, from its id and type.
+pub fn object_getxattr(oid: git2::Oid, kind: git2::ObjectType, name: &OsStr)
+                       -> Result<Vec<u8>, libc::c_int> {
+    if name == OsStr::new(XATTR_OID) {
+        Ok(oid.to_string().into_bytes())
+    } else if name == OsStr::new(XATTR_TYPE) {
+        Ok(kind.str().as_bytes().to_vec())
+    } else {
+        Err(libc::ENODATA)
     }
 }
 
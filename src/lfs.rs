@@ -0,0 +1,74 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::{Path, PathBuf};
+use std::str;
+
+
+/// Pointer files are small, so anything bigger isn't worth parsing.
+pub const MAX_POINTER_SIZE: usize = 1024;
+
+
+/// A parsed Git LFS pointer, standing in for the real content stored elsewhere
+pub struct Pointer {
+    oid: String,
+    size: u64,
+}
+
+impl Pointer {
+    /// Parse blob content as an LFS pointer, if that's what it is.
+    ///
+    /// The format is a few `key value` lines, starting with `version` and including at least
+    /// `oid sha256:<hex>` and `size <bytes>`.
+    pub fn parse(data: &[u8]) -> Option<Pointer> {
+        if data.len() > MAX_POINTER_SIZE {
+            return None;
+        }
+        let text = match str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return None,
+        };
+
+        let mut lines = text.lines();
+        match lines.next() {
+            Some(line) if line.starts_with("version https://git-lfs.github.com/spec/") => (),
+            _ => return None,
+        }
+
+        let mut oid = None;
+        let mut size = None;
+        for line in lines {
+            if line.starts_with("oid sha256:") {
+                let hex = &line["oid sha256:".len()..];
+                if hex.len() == 64 && hex.bytes().all(|b| b"0123456789abcdef".contains(&b)) {
+                    oid = Some(hex.to_string());
+                }
+            } else if line.starts_with("size ") {
+                size = line["size ".len()..].parse().ok();
+            }
+        }
+
+        match (oid, size) {
+            (Some(oid), Some(size)) => Some(Pointer { oid: oid, size: size }),
+            _ => None,
+        }
+    }
+
+    /// The size of the real content, as recorded in the pointer.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Where the real content is kept in the local object store, if it has been fetched.
+    pub fn path(&self, git_dir: &Path) -> PathBuf {
+        git_dir.join("lfs").join("objects")
+            .join(&self.oid[0..2])
+            .join(&self.oid[2..4])
+            .join(&self.oid)
+    }
+}
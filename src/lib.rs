@@ -8,97 +8,264 @@
 
 //! # GitFS: a FUSE filesystem for Git objects
 
-#![cfg_attr(feature="probe", feature(asm))]
-
 #![deny(missing_docs)]
 
 
 #[cfg(feature="probe")]
-#[macro_use] #[no_link]
+#[macro_use]
 extern crate probe;
 
+#[macro_use]
+extern crate log;
+
+extern crate flate2;
 extern crate fuse;
 extern crate git2;
 extern crate libc;
 extern crate time;
 
-use fuse::FileType;
-
-use std::collections::hash_map;
+use std::borrow::Cow;
 use std::default::Default;
-use std::ffi::{CString, OsString};
+use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::u64;
 
-use inode::{Id, InodeContainer, InodeMapper};
+pub use fuse::{FileAttr, FileType};
+pub use control::Commands;
+pub use request::{Reply, Request};
+pub use vfs::{ROOT_INO, Statfs, Vfs};
 
+
+// NULL implementation of probe!(), for builds without USDT probes
+#[cfg(not(feature="probe"))]
+macro_rules! probe(
+    ($provider:ident, $name:ident) => ();
+    ($provider:ident, $name:ident, $($arg:expr),*)
+    => (if false { match ($($arg,)*) { _ => () }});
+);
+
+mod archive;
+mod attr;
+mod cache;
+mod control;
+mod daemon;
+mod filter;
+mod glob;
 mod inode;
 mod blob;
+mod lfs;
+mod merge;
+mod missing;
+mod overlay;
 mod tree;
 mod reference;
+mod request;
 mod root;
+mod scan;
+mod trace;
+mod vfs;
+mod worktree;
 
 
-const TTY: time::Timespec = time::Timespec { sec: 1, nsec: 0 };
 
 
-// NULL implementation of probe!()
-// XXX would be nice if libprobe could toggle feature(asm) itself
-// ... based on whether a nightly compiler is active.
-#[cfg(not(feature="probe"))]
-macro_rules! probe(
-    ($provider:ident, $name:ident) => ();
-    ($provider:ident, $name:ident, $($arg:expr),*)
-    => (if false { match ($($arg,)*) { _ => () }});
-);
+/// Selects what the root directory of the filesystem presents.
+#[derive(Clone, Debug)]
+pub enum RootView {
+    /// The whole repository, with `HEAD` and `refs/` at the top level.
+    Repository,
+    /// The tree of a single revision, e.g. `v2.3` or `HEAD~2:src`.
+    Revision(String),
+    /// The trees of several revisions stacked as layers, each named like `("base", "main")`
+    /// and lowest first.  Entries come from the topmost layer that has them, and directories
+    /// are merged, but whatever a layer deleted since it forked from the one below is hidden.
+    Overlay(Vec<(String, String)>),
+}
+
+impl Default for RootView {
+    fn default() -> RootView {
+        RootView::Repository
+    }
+}
+
+
+/// How to treat Git LFS pointer files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LfsPolicy {
+    /// Serve pointer files as-is.
+    Disabled,
+    /// Serve the LFS object in place of its pointer, or the pointer itself if the object
+    /// isn't available locally.
+    FallbackToPointer,
+    /// Serve the LFS object in place of its pointer, or fail reads with `EIO` if the object
+    /// isn't available locally.
+    Required,
+}
+
+
+/// Tunable behavior of a GitFS.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// How to treat Git LFS pointer files, found in `GIT_DIR/lfs/objects/`.
+    pub lfs: LfsPolicy,
+    /// Whether to serve file content as `git checkout` would write it, with `.gitattributes`
+    /// conversions for line endings, `ident`, and `filter` smudge commands.
+    pub checkout: bool,
+    /// How long a `filter` smudge command may run with `checkout`, before it's killed and the
+    /// read fails with `EIO`.
+    pub filter_timeout: time::Timespec,
+    /// Whether files with the same content within a commit's tree share one inode, with a
+    /// link count of how many times they appear, rather than each being separate with a link
+    /// count of 1.  This doesn't apply with `checkout`, where content also depends on the path.
+    pub hardlinks: bool,
+    /// The most blob content to keep in memory at once, in bytes, shared by every file with
+    /// the same content.  The least recently used content is evicted first.
+    pub cache_size: usize,
+    /// How long the kernel may cache the entries and attributes of Git objects, which never
+    /// change once they're found by oid.
+    pub object_ttl: time::Timespec,
+    /// How long the kernel may cache whatever can change, like the directories of references
+    /// and which commit HEAD is.
+    pub ref_ttl: time::Timespec,
+    /// How long the kernel may remember that a name wasn't found, outside of Git trees where
+    /// that can't change.  Zero disables caching missing names there.
+    pub negative_ttl: time::Timespec,
+    /// A local repository to copy objects from when they're missing, e.g. the origin of a
+    /// partial clone.  Otherwise missing objects of partial and shallow clones fail to read
+    /// with `ENODATA`, and have a `user.git.missing` xattr saying why.
+    pub promisor: Option<PathBuf>,
+    /// Patterns of the only paths to show within trees, like `src/**`, or everything if empty.
+    /// These match like `.gitignore` patterns, and directories they match show everything
+    /// within.  Directories are also shown if they might contain a match.
+    pub include: Vec<String>,
+    /// Patterns of paths to hide within trees, like `**/*.bin`, even if they're included.
+    pub exclude: Vec<String>,
+    /// Whether to show only the paths included by `GIT_DIR/info/sparse-checkout`, if it exists.
+    pub sparse: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            lfs: LfsPolicy::FallbackToPointer,
+            checkout: false,
+            filter_timeout: time::Timespec::new(10, 0),
+            hardlinks: false,
+            cache_size: 64 << 20,
+            object_ttl: time::Timespec::new(365 * 24 * 60 * 60, 0),
+            ref_ttl: time::Timespec::new(1, 0),
+            negative_ttl: time::Timespec::new(1, 0),
+            promisor: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            sparse: false,
+        }
+    }
+}
 
 
 /// The main object implementing a FUSE filesystem.
 pub struct GitFS {
-    repo: git2::Repository,
-    epoch: time::Timespec,
-    uid: u32,
-    gid: u32,
-    mapper: InodeMapper,
-    inodes: InodeContainer,
+    vfs: Vfs,
     mountdir: Option<DirHandle>,
 }
 
 impl GitFS {
-    /// Create a GitFS referencing the given GIT_DIR.
-    pub fn new<P: AsRef<Path>>(git_dir: &P) -> Result<GitFS, git2::Error> {
-        Ok(GitFS {
-            repo: try!(git2::Repository::open(git_dir.as_ref())),
-            epoch: time::get_time(),
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
-            mapper: Default::default(),
-            inodes: Default::default(),
+    /// Create a GitFS referencing the given GIT_DIR, with the given view at its root.
+    ///
+    /// A `RootView::Revision` or `RootView::Overlay` is resolved right away, so the mounted tree
+    /// won't change even if a revspec later names something else, like a moving branch.
+    pub fn new<P: AsRef<Path>>(git_dir: &P, root: RootView) -> Result<GitFS, git2::Error> {
+        Vfs::new(git_dir, root).map(GitFS::from_vfs)
+    }
+
+    /// Create a GitFS serving every repository found directly within `dir`, each as a top-level
+    /// directory named like its entry in `dir`, with the usual HEAD and refs/ view inside.
+    pub fn scan<P: AsRef<Path>>(dir: &P) -> Result<GitFS, git2::Error> {
+        Vfs::scan(dir).map(GitFS::from_vfs)
+    }
+
+    /// Create a GitFS to mount an existing Vfs.
+    pub fn from_vfs(vfs: Vfs) -> GitFS {
+        GitFS {
+            vfs: vfs,
             mountdir: None,
-        })
+        }
+    }
+
+    /// Get the options to adjust, before mounting.
+    pub fn options_mut(&mut self) -> &mut Options {
+        self.vfs.options_mut()
     }
 
-    /// Get the resolved GIT_DIR.
+    /// Get the resolved GIT_DIR, or the scanned directory when serving many repositories.
     pub fn git_dir(&self) -> &Path {
-        self.repo.path()
+        self.vfs.git_dir()
     }
 
     fn mount_options(&self) -> OsString {
-        let mut options = OsString::from("-oro,default_permissions,fsname=");
-        options.push(&self.repo.path()); // FIXME escape commas?
+        // NB: Not mounted "ro", so the control files can be written, but everything else
+        // refuses to be modified with EROFS anyway.
+        let mut options = OsString::from("-odefault_permissions,fsname=");
+        options.push(self.git_dir()); // FIXME escape commas?
         options
     }
 
     /// Mount the filesystem and wait until the path is unmounted, e.g. with the command
-    /// `fusermount -u PATH`.
-    pub fn mount<P: AsRef<Path>>(mut self, mountpoint: &P) {
+    /// `fusermount -u PATH`.  SIGINT and SIGTERM will also unmount it cleanly, while SIGHUP
+    /// rereads all references and SIGUSR1 drops cached inodes and content.
+    pub fn mount<P: AsRef<Path>>(self, mountpoint: &P) -> io::Result<()> {
+        let commands = self.vfs.commands();
+        let mut session = try!(self.session(mountpoint.as_ref()));
+        try!(daemon::handle_signals(mountpoint.as_ref(), commands));
+        session.run()
+    }
+
+    /// Mount the filesystem, then detach into the background and serve it until the path is
+    /// unmounted, or SIGINT or SIGTERM is received, handling SIGHUP and SIGUSR1 like `mount`.
+    /// Mount errors are returned before detaching, so they can still be reported, and the
+    /// calling process only exits once the daemon has started, printing any error it had.
+    /// If given, the daemon's PID is written to `pid_file` by then, and that file is removed
+    /// again when the daemon exits.
+    pub fn daemonize<P: AsRef<Path>>(mut self, mountpoint: &P, pid_file: Option<&Path>)
+                                     -> io::Result<()> {
+        // Resolve paths now, as the daemon won't stay in the current directory
+        let mountpoint = try!(daemon::absolute(mountpoint.as_ref()));
+        let pid_file = match pid_file {
+            Some(path) => Some(try!(daemon::absolute(path))),
+            None => None,
+        };
+        if let Some(promisor) = self.options_mut().promisor.take() {
+            self.options_mut().promisor = Some(try!(daemon::absolute(&promisor)));
+        }
+
+        let commands = self.vfs.commands();
+        let mut session = try!(self.session(&mountpoint));
+        let ready = try!(daemon::detach());
+
+        let pid_file = match pid_file {
+            Some(ref path) => daemon::PidFile::create(path).map(Some).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            }),
+            None => Ok(None),
+        };
+        let started = pid_file.and_then(|pid_file| {
+            daemon::handle_signals(&mountpoint, commands).map(|()| pid_file)
+        });
+        // The parent exits once this is reported, so the PID file is ready for its caller
+        let _pid_file = try!(ready.report(started));
+        session.run()
+    }
+
+    /// Establish the mount, without serving any requests yet.
+    fn session(mut self, mountpoint: &Path) -> io::Result<fuse::Session<GitFS>> {
         // Create/remove the mount point if it doesn't exist
-        self.mountdir = DirHandle::new(mountpoint.as_ref());
+        self.mountdir = DirHandle::new(mountpoint);
 
         let options = self.mount_options();
-        fuse::mount(self, mountpoint, &[&options])
+        fuse::Session::new(self, mountpoint, &[&options])
     }
 
     /// Mount the filesystem in the background.  It will remain mounted until the returned session
@@ -110,76 +277,145 @@ impl GitFS {
         let options = self.mount_options();
         fuse::spawn_mount(self, mountpoint, &[&options])
     }
+}
+
+impl GitFS {
+    /// Answer a request as the mount would, logging it like any other operation.  This is all
+    /// that the `fuse::Filesystem` methods do besides firing probes and sending the reply, so
+    /// it's also how their answers can be checked without a mount.
+    pub fn handle(&mut self, request: Request) -> Reply {
+        match request {
+            Request::Lookup(parent, name) => {
+                match trace::Span::new("lookup", parent).finish(self.vfs.lookup(parent, name)) {
+                    Ok(attr) => Reply::Entry(self.vfs.entry_ttl(parent, attr.ino), attr, 1),
+                    Err(libc::ENOENT) => {
+                        // A zero inode tells the kernel to remember that the name is missing
+                        let ttl = self.vfs.negative_ttl(parent);
+                        if ttl > time::Timespec::new(0, 0) {
+                            Reply::Entry(ttl, negative_attr(), 0)
+                        } else {
+                            Reply::Error(libc::ENOENT)
+                        }
+                    },
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Getattr(ino) => {
+                match trace::Span::new("getattr", ino).finish(self.vfs.getattr(ino)) {
+                    Ok(attr) => Reply::Attr(self.vfs.attr_ttl(ino), attr),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Open(ino, flags) => {
+                match trace::Span::new("open", ino).finish(self.vfs.open(ino, flags)) {
+                    Ok(flags) => Reply::Opened(flags),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Read(ino, offset, size) => {
+                match trace::Span::new("read", ino).finish(self.vfs.read(ino, offset, size)) {
+                    Ok(data) => Reply::Data(Cow::Borrowed(data)),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Release(ino) => {
+                match trace::Span::new("release", ino).finish(self.vfs.release(ino)) {
+                    Ok(()) => Reply::Ok,
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Readdir(ino, offset, add) => {
+                let span = trace::Span::new("readdir", ino);
+                match span.finish(self.vfs.readdir(ino, offset, add)) {
+                    Ok(()) => Reply::Ok,
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Statfs(ino) => {
+                match trace::Span::new("statfs", ino).finish(self.vfs.statfs()) {
+                    Ok(st) => Reply::Statfs(st),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Getxattr(ino, name, size) => {
+                match trace::Span::new("getxattr", ino).finish(self.vfs.getxattr(ino, name)) {
+                    Ok(value) => xattr_reply(value, size),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Listxattr(ino, size) => {
+                match trace::Span::new("listxattr", ino).finish(self.vfs.listxattr(ino)) {
+                    Ok(names) => {
+                        let mut list = Vec::new();
+                        for name in names {
+                            list.extend_from_slice(name.as_bytes());
+                            list.push(0);
+                        }
+                        xattr_reply(list, size)
+                    },
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Access(ino, mask, uid, gid) => {
+                let result = self.vfs.access(ino, mask, uid, gid);
+                match trace::Span::new("access", ino).finish(result) {
+                    Ok(()) => Reply::Ok,
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            Request::Setattr(ino, size) => {
+                match trace::Span::new("setattr", ino).finish(self.vfs.setattr(ino, size)) {
+                    Ok(attr) => Reply::Attr(self.vfs.attr_ttl(ino), attr),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
 
-    fn defattr(&self, ino: u64) -> fuse::FileAttr {
-        fuse::FileAttr {
-            ino: ino,
-            size: 0,
-            blocks: 0,
-            atime: self.epoch,
-            mtime: self.epoch,
-            ctime: self.epoch,
-            crtime: self.epoch,
-            kind: FileType::RegularFile, /* unknown... */
-            perm: 0,
-            nlink: 1,
-            uid: self.uid,
-            gid: self.gid,
-            rdev: 0,
-            flags: 0,
+            Request::Write(ino, offset, data) => {
+                let result = self.vfs.write(ino, offset, data);
+                match trace::Span::new("write", ino).finish(result) {
+                    Ok(size) => Reply::Written(size),
+                    Err(rc) => Reply::Error(rc),
+                }
+            },
+
+            // Everything else that would modify the filesystem is refused, since it's
+            // read-only apart from the control files that take commands.
+            Request::Modify(op, ino) => Reply::Error(trace::read_only(op, ino)),
         }
     }
 }
 
 impl fuse::Filesystem for GitFS {
     fn init (&mut self, _req: &fuse::Request) -> Result<(), libc::c_int> {
-        let root_ino = self.mapper.new_ino();
-        let head_ino = self.mapper.new_ino();
-        let refs_ino = self.mapper.new_ino();
-        assert_eq!(fuse::FUSE_ROOT_ID, root_ino);
-
-        let root = root::Root::new(Id::Ino(head_ino), Id::Ino(refs_ino));
-        self.inodes.insert(root_ino, root);
-
-        let refs = reference::RefDir::new();
-        self.inodes.insert(refs_ino, refs);
-
-        Ok(())
+        trace::Span::new("init", ROOT_INO).finish(self.vfs.init())
     }
 
-    fn lookup(&mut self, _req: &fuse::Request, parent: u64, name: &Path, reply: fuse::ReplyEntry) {
+    fn lookup (&mut self, _req: &fuse::Request, parent: u64, name: &OsStr,
+               reply: fuse::ReplyEntry) {
+        let name = Path::new(name);
         if let Ok(name) = CString::new(name.as_os_str().as_bytes()) {
             probe!(gitfs, lookup, parent, name.as_ptr());
         }
 
-        let repo = &self.repo;
-        let id = {
-            let inode = self.inodes.find_mut(parent);
-            match inode.and_then(|inode| inode.lookup(repo, name)) {
-                Ok(id) => id,
-                Err(rc) => return reply.error(rc),
-            }
-        };
-        let ino = self.mapper.get_ino(id);
-
-        if let hash_map::Entry::Vacant(entry) = self.inodes.entry(ino) {
-            if let Some(oid) = self.mapper.get_oid(ino) {
-                if let Some(inode) = inode::new_inode(&self.repo, oid) {
-                    entry.insert(inode);
-                }
-            }
-        }
-
-        let attr = self.defattr(ino);
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| inode.getattr(repo, attr)) {
-            Ok(attr) => reply.entry(&TTY, &attr, 1),
-            Err(rc) => reply.error(rc),
+        match self.handle(Request::Lookup(parent, name)) {
+            Reply::Entry(ttl, attr, generation) => reply.entry(&ttl, &attr, generation),
+            other => reply.error(other.errno()),
         }
     }
 
-    fn forget (&mut self, _req: &fuse::Request, _ino: u64, _nlookup: u64) {
-        probe!(gitfs, forget, _ino, _nlookup);
+    fn forget (&mut self, _req: &fuse::Request, ino: u64, _nlookup: u64) {
+        probe!(gitfs, forget, ino, _nlookup);
+        trace::Span::new("forget", ino).finish(Ok(())).ok();
 
         // TODO could probably drop Oid inodes, since they're easily recreated
     }
@@ -188,34 +424,28 @@ impl fuse::Filesystem for GitFS {
                 reply: fuse::ReplyAttr) {
         probe!(gitfs, getattr, ino);
 
-        let attr = self.defattr(ino);
-        let repo = &self.repo;
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| inode.getattr(repo, attr)) {
-            Ok(attr) => reply.attr(&TTY, &attr),
-            Err(rc) => reply.error(rc),
+        match self.handle(Request::Getattr(ino)) {
+            Reply::Attr(ttl, attr) => reply.attr(&ttl, &attr),
+            other => reply.error(other.errno()),
         }
     }
 
     fn open (&mut self, _req: &fuse::Request, ino: u64, flags: u32, reply: fuse::ReplyOpen) {
         probe!(gitfs, open, ino, flags);
 
-        let repo = &self.repo;
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| inode.open(repo, flags)) {
-            Ok(flags) => reply.opened(0, flags),
-            Err(rc) => reply.error(rc),
+        match self.handle(Request::Open(ino, flags)) {
+            Reply::Opened(flags) => reply.opened(0, flags),
+            other => reply.error(other.errno()),
         }
     }
-    fn read (&mut self, _req: &fuse::Request, ino: u64, _fh: u64, offset: u64, size: u32,
+    fn read (&mut self, _req: &fuse::Request, ino: u64, _fh: u64, offset: i64, size: u32,
              reply: fuse::ReplyData) {
+        let offset = offset as u64;
         probe!(gitfs, read, ino, offset, size);
 
-        let repo = &self.repo;
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| inode.read(repo, offset, size)) {
-            Ok(data) => reply.data(data),
-            Err(rc) => reply.error(rc),
+        match self.handle(Request::Read(ino, offset, size)) {
+            Reply::Data(data) => reply.data(&data),
+            other => reply.error(other.errno()),
         }
     }
 
@@ -223,39 +453,182 @@ impl fuse::Filesystem for GitFS {
                 _lock_owner: u64, _flush: bool, reply: fuse::ReplyEmpty) {
         probe!(gitfs, release, ino);
 
-        let repo = &self.repo;
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| inode.release(repo)) {
-            Ok(()) => reply.ok(),
-            Err(rc) => reply.error(rc),
+        match self.handle(Request::Release(ino)) {
+            Reply::Ok => reply.ok(),
+            other => reply.error(other.errno()),
         }
     }
 
-    fn readdir (&mut self, _req: &fuse::Request, ino: u64, _fh: u64, mut offset: u64,
+    fn readdir (&mut self, _req: &fuse::Request, ino: u64, _fh: u64, offset: i64,
                 mut reply: fuse::ReplyDirectory) {
+        let offset = offset as u64;
         probe!(gitfs, readdir, ino, offset);
 
-        let mapper = &mut self.mapper;
-        let repo = &self.repo;
-        let inode = self.inodes.find_mut(ino);
-        match inode.and_then(|inode| {
-            if offset == 0 {
-                offset += 1;
-                reply.add(u64::MAX, offset, FileType::Directory, &Path::new("."));
-            }
-            if offset == 1 {
-                offset += 1;
-                reply.add(u64::MAX, offset, FileType::Directory, &Path::new(".."));
-            }
-            inode.readdir(repo, offset - 2, Box::new(|id, kind, path| {
-                offset += 1;
-                reply.add(mapper.get_ino(id), offset, kind, path)
-            }))
-        }) {
-            Ok(()) => reply.ok(),
-            Err(rc) => reply.error(rc),
+        // NB: fuse 0.3 can't negotiate READDIRPLUS, so the kernel still looks up each entry
+        // for `ls -l`, but that only reads object headers.  `Vfs::readdirplus` is ready for
+        // when it can.
+        let result = self.handle(Request::Readdir(ino, offset, &mut |ino, offset, kind, name| {
+            reply.add(ino, offset as i64, kind, name)
+        }));
+        match result {
+            Reply::Ok => reply.ok(),
+            other => reply.error(other.errno()),
+        }
+    }
+
+    fn statfs (&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyStatfs) {
+        probe!(gitfs, statfs, ino);
+
+        match self.handle(Request::Statfs(ino)) {
+            Reply::Statfs(st) => {
+                reply.statfs(st.blocks, 0, 0, st.files, 0, st.bsize, st.namelen, st.bsize)
+            },
+            other => reply.error(other.errno()),
+        }
+    }
+
+    fn getxattr (&mut self, _req: &fuse::Request, ino: u64, name: &OsStr, size: u32,
+                 reply: fuse::ReplyXattr) {
+        probe!(gitfs, getxattr, ino, size);
+
+        match self.handle(Request::Getxattr(ino, name, size)) {
+            Reply::Size(size) => reply.size(size),
+            Reply::Data(data) => reply.data(&data),
+            other => reply.error(other.errno()),
+        }
+    }
+
+    fn listxattr (&mut self, _req: &fuse::Request, ino: u64, size: u32,
+                  reply: fuse::ReplyXattr) {
+        probe!(gitfs, listxattr, ino, size);
+
+        match self.handle(Request::Listxattr(ino, size)) {
+            Reply::Size(size) => reply.size(size),
+            Reply::Data(data) => reply.data(&data),
+            other => reply.error(other.errno()),
+        }
+    }
+
+    fn access (&mut self, req: &fuse::Request, ino: u64, mask: u32, reply: fuse::ReplyEmpty) {
+        probe!(gitfs, access, ino, mask);
+
+        match self.handle(Request::Access(ino, mask, req.uid(), req.gid())) {
+            Reply::Ok => reply.ok(),
+            other => reply.error(other.errno()),
+        }
+    }
+
+    // Everything that would modify the filesystem is refused, since it's read-only, except
+    // for truncating and writing the control files that take commands.
+
+    fn setattr (&mut self, _req: &fuse::Request, ino: u64, mode: Option<u32>,
+                uid: Option<u32>, gid: Option<u32>, size: Option<u64>,
+                _atime: Option<time::Timespec>, _mtime: Option<time::Timespec>,
+                _fh: Option<u64>, _crtime: Option<time::Timespec>,
+                _chgtime: Option<time::Timespec>, _bkuptime: Option<time::Timespec>,
+                _flags: Option<u32>, reply: fuse::ReplyAttr) {
+        let request = if mode.is_some() || uid.is_some() || gid.is_some() {
+            Request::Modify("setattr", ino)
+        } else {
+            Request::Setattr(ino, size)
+        };
+        match self.handle(request) {
+            Reply::Attr(ttl, attr) => reply.attr(&ttl, &attr),
+            other => reply.error(other.errno()),
         }
     }
+
+    fn mknod (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr, _mode: u32,
+              _rdev: u32, reply: fuse::ReplyEntry) {
+        reply.error(self.handle(Request::Modify("mknod", parent)).errno());
+    }
+
+    fn mkdir (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr, _mode: u32,
+              reply: fuse::ReplyEntry) {
+        reply.error(self.handle(Request::Modify("mkdir", parent)).errno());
+    }
+
+    fn unlink (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr,
+               reply: fuse::ReplyEmpty) {
+        reply.error(self.handle(Request::Modify("unlink", parent)).errno());
+    }
+
+    fn rmdir (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr,
+              reply: fuse::ReplyEmpty) {
+        reply.error(self.handle(Request::Modify("rmdir", parent)).errno());
+    }
+
+    fn symlink (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr, _link: &Path,
+                reply: fuse::ReplyEntry) {
+        reply.error(self.handle(Request::Modify("symlink", parent)).errno());
+    }
+
+    fn rename (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr, _newparent: u64,
+               _newname: &OsStr, reply: fuse::ReplyEmpty) {
+        reply.error(self.handle(Request::Modify("rename", parent)).errno());
+    }
+
+    fn link (&mut self, _req: &fuse::Request, ino: u64, _newparent: u64, _newname: &OsStr,
+             reply: fuse::ReplyEntry) {
+        reply.error(self.handle(Request::Modify("link", ino)).errno());
+    }
+
+    fn write (&mut self, _req: &fuse::Request, ino: u64, _fh: u64, offset: i64, data: &[u8],
+              _flags: u32, reply: fuse::ReplyWrite) {
+        match self.handle(Request::Write(ino, offset as u64, data)) {
+            Reply::Written(size) => reply.written(size),
+            other => reply.error(other.errno()),
+        }
+    }
+
+    fn setxattr (&mut self, _req: &fuse::Request, ino: u64, _name: &OsStr, _value: &[u8],
+                 _flags: u32, _position: u32, reply: fuse::ReplyEmpty) {
+        reply.error(self.handle(Request::Modify("setxattr", ino)).errno());
+    }
+
+    fn removexattr (&mut self, _req: &fuse::Request, ino: u64, _name: &OsStr,
+                    reply: fuse::ReplyEmpty) {
+        reply.error(self.handle(Request::Modify("removexattr", ino)).errno());
+    }
+
+    fn create (&mut self, _req: &fuse::Request, parent: u64, _name: &OsStr, _mode: u32,
+               _flags: u32, reply: fuse::ReplyCreate) {
+        reply.error(self.handle(Request::Modify("create", parent)).errno());
+    }
+}
+
+
+/// Reply with xattr data, or just its size if the caller is asking how big a buffer to use.
+fn xattr_reply<'a>(data: Vec<u8>, size: u32) -> Reply<'a> {
+    if size == 0 {
+        Reply::Size(data.len() as u32)
+    } else if data.len() > size as usize {
+        Reply::Error(libc::ERANGE)
+    } else {
+        Reply::Data(Cow::Owned(data))
+    }
+}
+
+
+/// The attributes of a negative entry, which only needs its zero inode number.
+fn negative_attr() -> FileAttr {
+    let zero = time::Timespec::new(0, 0);
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: zero,
+        mtime: zero,
+        ctime: zero,
+        crtime: zero,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
 }
 
 
@@ -8,36 +8,268 @@
 
 //! # git-fs: command-line tool to mount Git objects
 //!
-//! Usage: git-fs [GIT_DIR [MOUNTPOINT]]
+//! Usage: git-fs [OPTIONS] [GIT_DIR [MOUNTPOINT]]
 //!
 //! - GIT_DIR: The directory of a git repository.  A bare git directory is fine,
 //! or if given as a working directory, it will automatically use the .git/
 //! directory within.  Defaults to the current directory.
 //!
 //! - MOUNTPOINT: The target to mount the filesystem.  Defaults to GIT_DIR/fs.
+//!
+//! Options:
+//!
+//! - `-f`, `--foreground`: Stay in the foreground, rather than detaching once mounted.
+//!
+//! - `--pid-file FILE`: Write the daemon's PID to FILE, removed again on exit.
+//!
+//! - `--rev REV`: Mount just the tree of revision REV at the root, rather than
+//! the whole repository.
+//!
+//! - `--overlay NAME=REV`: Mount the trees of several revisions stacked as
+//! layers, lowest first, e.g. `--overlay base=main --overlay top=feature`.
+//!
+//! - `--lfs MODE`: How to treat Git LFS pointers: `off` to show pointers as-is,
+//! `pointer` to show LFS objects but fall back to the pointer when an object
+//! isn't available locally (the default), or `error` to fail reads instead.
+//!
+//! - `--checkout`: Serve file content as `git checkout` would write it, applying
+//! `.gitattributes` conversions for line endings, `ident`, and filters.
+//!
+//! - `--filter-timeout SECONDS`: How long a filter may run with `--checkout`
+//! before it's killed and the read fails.  The default is 10.
+//!
+//! - `--hardlinks`: Show files with identical content within a commit as hard
+//! links of one inode.
+//!
+//! - `--cache-size SIZE`: Keep up to SIZE bytes of file content in memory,
+//! with an optional `K`, `M` or `G` suffix.  The default is `64M`.
+//!
+//! - `--object-ttl SECONDS`: How long the kernel may cache Git objects, which
+//! never change.  The default is a year.
+//!
+//! - `--ref-ttl SECONDS`: How long the kernel may cache references and anything
+//! else that can change.  The default is 1.
+//!
+//! - `--negative-ttl SECONDS`: How long the kernel may remember a missing name,
+//! outside of Git trees.  The default is 1, and 0 disables it.
+//!
+//! - `--promisor DIR`: Copy objects missing from a partial clone out of the
+//! local repository DIR, as they're needed.
+//!
+//! - `--include PATTERN`: Show only paths matching PATTERN within trees, like
+//! `src/**`.  This may be given more than once.
+//!
+//! - `--exclude PATTERN`: Hide paths matching PATTERN within trees, like
+//! `**/*.bin`.  This may be given more than once.
+//!
+//! - `--sparse`: Show only paths included by the repository's sparse-checkout
+//! patterns.
+//!
+//! - `--scan`: Treat GIT_DIR as a directory of repositories, and mount each one
+//! found there as a top-level directory.
+//!
+//! - `--log FILE`: Write a log to FILE, or to stderr for `-`, which needs `-f`.
+//!
+//! - `--log-level LEVEL`: Log messages up to LEVEL: `error`, `warn`, `info`
+//! (the default), `debug`, or `trace` for every operation and its latency.
 
+extern crate getopts;
 extern crate gitfs;
+extern crate log;
+extern crate time;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+fn usage(opts: &getopts::Options) -> String {
+    opts.usage("Usage: git-fs [OPTIONS] [GIT_DIR [MOUNTPOINT]]")
+}
+
+fn fail(message: &str) -> ! {
+    writeln!(io::stderr(), "git-fs: {}", message).ok();
+    process::exit(1);
+}
+
+/// Writes log records with a timestamp, to stderr or a file
+struct Logger {
+    out: Mutex<Box<Write + Send>>,
+    level: log::LogLevelFilter,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::LogRecord) {
+        if self.enabled(record.metadata()) {
+            if let Ok(mut out) = self.out.lock() {
+                writeln!(out, "{} {} {}: {}", time::now_utc().rfc3339(), record.level(),
+                         record.target(), record.args()).ok();
+            }
+        }
+    }
+}
 
-use std::ffi::{OsStr, OsString};
-use std::path::Path;
+/// Parse a size in bytes, with an optional binary suffix like `64M`.
+fn parse_size(size: &str) -> Option<usize> {
+    let (digits, shift) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<usize>().ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+}
+
+/// Parse option `name` as a whole number of seconds, if it was given.
+fn parse_ttl(matches: &getopts::Matches, name: &str, ttl: &mut time::Timespec) {
+    if let Some(seconds) = matches.opt_str(name) {
+        match seconds.parse() {
+            Ok(seconds) => *ttl = time::Timespec::new(seconds, 0),
+            Err(_) => fail(&format!("invalid --{} '{}'", name, seconds)),
+        }
+    }
+}
+
+/// Send log records to `path`, or to stderr for "-".
+fn init_logger(path: &str, level: log::LogLevelFilter) {
+    let out: Box<Write + Send> = if path == "-" {
+        Box::new(io::stderr())
+    } else {
+        match fs::OpenOptions::new().append(true).create(true).open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => fail(&format!("{}: {}", path, e)),
+        }
+    };
+    log::set_logger(|max_level| {
+        max_level.set(level);
+        Box::new(Logger { out: Mutex::new(out), level: level })
+    }).ok();
+}
 
 fn main() {
-    let args: Vec<OsString> = std::env::args_os().collect();
+    let mut opts = getopts::Options::new();
+    opts.optflag("f", "foreground", "stay in the foreground, rather than detaching once mounted");
+    opts.optopt("", "pid-file", "write the daemon's PID to FILE", "FILE");
+    opts.optopt("", "rev", "mount only the tree of revision REV", "REV");
+    opts.optmulti("", "overlay", "stack the tree of REV as layer NAME, lowest first", "NAME=REV");
+    opts.optopt("", "lfs", "how to treat Git LFS pointers: off, pointer or error", "MODE");
+    opts.optflag("", "checkout", "apply .gitattributes conversions, as a checkout would");
+    opts.optopt("", "filter-timeout", "kill smudge filters after SECONDS", "SECONDS");
+    opts.optflag("", "hardlinks", "show identical files within a commit as hard links");
+    opts.optopt("", "cache-size", "keep up to SIZE bytes of content cached, e.g. 64M", "SIZE");
+    opts.optopt("", "object-ttl", "let the kernel cache Git objects for SECONDS", "SECONDS");
+    opts.optopt("", "ref-ttl", "let the kernel cache references for SECONDS", "SECONDS");
+    opts.optopt("", "negative-ttl", "let the kernel cache missing names for SECONDS", "SECONDS");
+    opts.optopt("", "promisor", "copy missing objects from the repository DIR", "DIR");
+    opts.optmulti("", "include", "show only paths matching PATTERN, e.g. 'src/**'", "PATTERN");
+    opts.optmulti("", "exclude", "hide paths matching PATTERN, e.g. '**/*.bin'", "PATTERN");
+    opts.optflag("", "sparse", "show only paths included by info/sparse-checkout");
+    opts.optflag("", "scan", "mount each repository found in GIT_DIR as a directory");
+    opts.optopt("", "log", "write a log to FILE, or - for stderr", "FILE");
+    opts.optopt("", "log-level", "log messages up to LEVEL, e.g. debug (default info)", "LEVEL");
+    opts.optflag("h", "help", "print this help");
+
+    let matches = match opts.parse(std::env::args_os().skip(1)) {
+        Ok(matches) => matches,
+        Err(e) => fail(&format!("{}\n{}", e, usage(&opts))),
+    };
+    if matches.opt_present("help") {
+        print!("{}", usage(&opts));
+        return;
+    }
+    let args = &matches.free;
+
+    if let Some(path) = matches.opt_str("log") {
+        // The daemon's stderr is /dev/null, so its log would be lost
+        if path == "-" && !matches.opt_present("foreground") {
+            fail("--log - needs --foreground, since the daemon has no stderr");
+        }
+        let level = match matches.opt_str("log-level") {
+            Some(level) => match level.parse() {
+                Ok(level) => level,
+                Err(_) => fail(&format!("unknown --log-level '{}'", level)),
+            },
+            None => log::LogLevelFilter::Info,
+        };
+        init_logger(&path, level);
+    }
 
     // If unspecified, source defaults to the current directory
-    let source: &OsStr = if args.len() > 1 { &args[1] } else { OsStr::new(".") };
-
-    match gitfs::GitFS::new(&source) {
-        Ok(fs) => {
-            // If unspecified, the target defaults to GIT_DIR/fs
-            let target = if args.len() > 2 {
-                Path::new(&args[2]).to_path_buf()
-            } else { 
-                fs.git_dir().join("fs")
-            };
-
-            fs.mount(&target);
+    let source: &OsStr = if args.len() > 0 { args[0].as_ref() } else { OsStr::new(".") };
+
+    let layers = matches.opt_strs("overlay");
+    let root = match matches.opt_str("rev") {
+        Some(_) if !layers.is_empty() => fail("--rev can't be used with --overlay"),
+        Some(rev) => gitfs::RootView::Revision(rev),
+        None if !layers.is_empty() => {
+            // Layers are named like NAME=REV, or just by their REV
+            gitfs::RootView::Overlay(layers.iter().map(|layer| match layer.find('=') {
+                Some(i) => (layer[..i].to_string(), layer[i + 1..].to_string()),
+                None => (layer.clone(), layer.clone()),
+            }).collect())
         },
-        Err(e) => panic!("{}", e),
+        None => gitfs::RootView::Repository,
+    };
+
+    let mut options = gitfs::Options::default();
+    match matches.opt_str("lfs").as_ref().map(|s| &s[..]) {
+        None => (),
+        Some("off") => options.lfs = gitfs::LfsPolicy::Disabled,
+        Some("pointer") => options.lfs = gitfs::LfsPolicy::FallbackToPointer,
+        Some("error") => options.lfs = gitfs::LfsPolicy::Required,
+        Some(mode) => fail(&format!("unknown --lfs mode '{}'", mode)),
+    }
+    options.checkout = matches.opt_present("checkout");
+    parse_ttl(&matches, "filter-timeout", &mut options.filter_timeout);
+    options.hardlinks = matches.opt_present("hardlinks");
+    if let Some(size) = matches.opt_str("cache-size") {
+        match parse_size(&size) {
+            Some(size) => options.cache_size = size,
+            None => fail(&format!("invalid --cache-size '{}'", size)),
+        }
+    }
+    parse_ttl(&matches, "object-ttl", &mut options.object_ttl);
+    parse_ttl(&matches, "ref-ttl", &mut options.ref_ttl);
+    parse_ttl(&matches, "negative-ttl", &mut options.negative_ttl);
+    options.promisor = matches.opt_str("promisor").map(PathBuf::from);
+    options.include = matches.opt_strs("include");
+    options.exclude = matches.opt_strs("exclude");
+    options.sparse = matches.opt_present("sparse");
+
+    // NB: Scanning names the directory in its own errors
+    let mut fs = if matches.opt_present("scan") {
+        if matches.opt_present("rev") || matches.opt_present("overlay") {
+            fail("--rev and --overlay can't be used with --scan");
+        }
+        gitfs::GitFS::scan(&source).unwrap_or_else(|e| fail(e.message()))
+    } else {
+        gitfs::GitFS::new(&source, root).unwrap_or_else(|e| {
+            fail(&format!("{}: {}", Path::new(source).display(), e.message()))
+        })
+    };
+    *fs.options_mut() = options;
+
+    // If unspecified, the target defaults to GIT_DIR/fs
+    let target = if args.len() > 1 {
+        Path::new(&args[1]).to_path_buf()
+    } else {
+        fs.git_dir().join("fs")
+    };
+
+    let result = if matches.opt_present("foreground") {
+        fs.mount(&target)
+    } else {
+        let pid_file = matches.opt_str("pid-file");
+        fs.daemonize(&target, pid_file.as_ref().map(Path::new))
     };
+    if let Err(e) = result {
+        fail(&format!("{}: {}", target.display(), e));
+    }
 }
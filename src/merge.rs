@@ -0,0 +1,338 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Previews of merging two revisions, computed in memory without touching any working tree.
+
+use fuse::{self, FileType};
+use git2;
+use libc;
+use std::cmp;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use filter::Filter;
+use inode;
+use inode::{FileAttr, Id, Inode};
+use reference;
+use trace;
+
+
+/// The name of the file listing the conflicts of a merge
+pub const CONFLICTS: &'static str = "CONFLICTS";
+
+/// Split the name of a merge like `ours...theirs` into its revisions, each escaped with
+/// `reference::escape` to fit in one file name.
+pub fn parse(name: &str) -> Option<(String, String)> {
+    name.find("...").and_then(|i| {
+        let (ours, theirs) = (&name[..i], &name[i + 3..]);
+        if ours.is_empty() || theirs.is_empty() {
+            None
+        } else {
+            Some((reference::unescape(ours), reference::unescape(theirs)))
+        }
+    })
+}
+
+
+/// The directory of merge previews, where `<ours>...<theirs>` can be looked up for any two
+/// revisions with common history, with slashes in their names escaped as `%2F`.  Nothing is
+/// listed.
+pub struct MergeList;
+
+impl MergeList {
+    pub fn new() -> Box<Inode+'static> {
+        Box::new(MergeList)
+    }
+}
+
+/// Find the commit of a revision being merged.
+fn find_commit(repo: &git2::Repository, rev: &str) -> Result<git2::Oid, libc::c_int> {
+    let object = try!(trace::git("revparse_single", || repo.revparse_single(rev))
+                      .map_err(|_| libc::ENOENT));
+    object.peel(git2::ObjectType::Commit)
+        .map(|commit| commit.id())
+        .map_err(|_| libc::ENOENT)
+}
+
+impl Inode for MergeList {
+    fn lookup(&mut self, repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        let (ours, theirs) = try!(name.to_str().and_then(parse).ok_or(libc::ENOENT));
+        let ours = try!(find_commit(repo, &ours));
+        let theirs = try!(find_commit(repo, &theirs));
+        // NB: Like `git merge`, refuse unrelated histories
+        try!(trace::git("merge_base", || repo.merge_base(ours, theirs))
+             .map_err(|_| libc::ENOENT));
+        Ok(Id::Merge(ours, theirs))
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        Ok(FileAttr {
+            size: 0,
+            blocks: 0,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, _repo: &git2::Repository, _offset: u64,
+               _add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        Ok(())
+    }
+}
+
+
+/// An entry of a merge result
+enum Node {
+    Dir(BTreeMap<Vec<u8>, Node>),
+    /// A blob that merged cleanly, with its mode
+    Blob(git2::Oid, u32),
+    /// Content made up by the merge, like a file with conflict markers
+    Data(Vec<u8>),
+}
+
+/// The result of merging two commits, from their merge base
+pub struct Merge {
+    root: BTreeMap<Vec<u8>, Node>,
+    /// Each conflicted path with its status, like `git status --short`
+    conflicts: Vec<u8>,
+}
+
+impl Merge {
+    /// Merge `ours` and `theirs` in memory, with conflict markers labeled by their names.
+    pub fn new(repo: &git2::Repository, ours: git2::Oid, theirs: git2::Oid, labels: (&str, &str)
+              ) -> Result<Merge, libc::c_int> {
+        let base = try!(trace::git_errno("merge_base", || repo.merge_base(ours, theirs)));
+        let base = try!(commit_tree(repo, base));
+        let (ours, theirs) = (try!(commit_tree(repo, ours)), try!(commit_tree(repo, theirs)));
+        let index = try!(trace::git_errno("merge_trees",
+                                          || repo.merge_trees(&base, &ours, &theirs, None)));
+
+        let mut merge = Merge {
+            root: BTreeMap::new(),
+            conflicts: Vec::new(),
+        };
+        for entry in index.iter() {
+            // NB: Conflicts are left at stages 1-3, and collected below
+            if (entry.flags >> 12) & 0x3 == 0 {
+                merge.insert(&entry.path, Node::Blob(entry.id, entry.mode));
+            }
+        }
+
+        let conflicts = try!(trace::git_errno("conflicts", || index.conflicts()));
+        for conflict in conflicts {
+            let conflict = try!(conflict.map_err(|e| trace::errno("conflicts", &e)));
+            let (status, path, data) = match (conflict.ancestor, conflict.our, conflict.their) {
+                (Some(base), Some(our), Some(their)) => {
+                    let mut options = git2::MergeFileOptions::new();
+                    options.ancestor_label("base").our_label(labels.0).their_label(labels.1);
+                    let result = try!(trace::git_errno("merge_file_from_index", || {
+                        repo.merge_file_from_index(&base, &our, &their, Some(&mut options))
+                    }));
+                    ("UU", our.path, result.content().to_vec())
+                },
+                (None, Some(our), Some(their)) => {
+                    let data = try!(whole_conflict(repo, labels, our.id, their.id));
+                    ("AA", our.path, data)
+                },
+                // Like git, what one side deleted and the other changed is left changed
+                (_, Some(our), None) => ("UD", our.path, try!(content(repo, our.id))),
+                (_, None, Some(their)) => ("DU", their.path, try!(content(repo, their.id))),
+                (_, None, None) => continue,
+            };
+            merge.conflicts.extend_from_slice(status.as_bytes());
+            merge.conflicts.push(b' ');
+            merge.conflicts.extend_from_slice(&path);
+            merge.conflicts.push(b'\n');
+            merge.insert(&path, Node::Data(data));
+        }
+        Ok(merge)
+    }
+
+    /// Add an entry at `path`, creating its directories as needed.
+    fn insert(&mut self, path: &[u8], node: Node) {
+        let mut names: Vec<_> = path.split(|&c| c == b'/').collect();
+        let last = names.pop().unwrap_or(path);
+        let mut dir = &mut self.root;
+        for name in names {
+            let entry = dir.entry(name.to_vec()).or_insert_with(|| Node::Dir(BTreeMap::new()));
+            // NB: A path can't be both, so a directory takes the place of a file
+            match *entry {
+                Node::Dir(_) => (),
+                _ => *entry = Node::Dir(BTreeMap::new()),
+            }
+            dir = match *entry {
+                Node::Dir(ref mut dir) => dir,
+                _ => return,
+            };
+        }
+        dir.insert(last.to_vec(), node);
+    }
+
+    /// Create the directory of this merge, at `path` in the filesystem, using `add` to give
+    /// an Id to each directory and conflicted file within.  Whatever `filter` hides is left
+    /// out, and the `CONFLICTS` file takes the place of any file of that name in the result.
+    pub fn build(self, path: &Path, filter: Option<&Filter>,
+                 add: &mut FnMut(&Path, Box<Inode+'static>) -> Id) -> Box<Inode+'static> {
+        let mut root = self.root;
+        root.insert(CONFLICTS.as_bytes().to_vec(), Node::Data(self.conflicts));
+        build_dir(root, path, Path::new(""), filter, add)
+    }
+}
+
+fn build_dir(nodes: BTreeMap<Vec<u8>, Node>, path: &Path, tree_path: &Path,
+             filter: Option<&Filter>, add: &mut FnMut(&Path, Box<Inode+'static>) -> Id
+            ) -> Box<Inode+'static> {
+    let mut entries = Vec::with_capacity(nodes.len());
+    for (name, node) in nodes {
+        let os_name = <OsStr as OsStrExt>::from_bytes(&name);
+        let (path, tree_path) = (path.join(os_name), tree_path.join(os_name));
+        let is_dir = match node { Node::Dir(_) => true, _ => false };
+        if filter.map_or(false, |filter| !filter.allows(&tree_path, is_dir)) {
+            continue;
+        }
+        let (id, kind) = match node {
+            Node::Dir(nodes) => {
+                let dir = build_dir(nodes, &path, &tree_path, filter, add);
+                (add(&path, dir), FileType::Directory)
+            },
+            Node::Blob(oid, 0o160000) => (Id::Oid(oid), FileType::CharDevice),
+            Node::Blob(oid, _) => (Id::Oid(oid), FileType::RegularFile),
+            Node::Data(data) => (add(&path, MergeFile::new(data)), FileType::RegularFile),
+        };
+        entries.push((name, id, kind));
+    }
+    MergeDir::new(entries)
+}
+
+/// Find the tree of a commit being merged.
+fn commit_tree(repo: &git2::Repository, oid: git2::Oid) -> Result<git2::Tree, libc::c_int> {
+    let commit = try!(trace::git_errno("find_commit", || repo.find_commit(oid)));
+    trace::git_errno("tree", || commit.tree())
+}
+
+/// Read the content of a blob being merged.
+fn content(repo: &git2::Repository, oid: git2::Oid) -> Result<Vec<u8>, libc::c_int> {
+    let blob = try!(trace::git_errno("find_blob", || repo.find_blob(oid)));
+    Ok(blob.content().to_vec())
+}
+
+/// Mark the whole content of both sides as conflicting, when there's no base to compare.
+fn whole_conflict(repo: &git2::Repository, labels: (&str, &str), ours: git2::Oid,
+                  theirs: git2::Oid) -> Result<Vec<u8>, libc::c_int> {
+    let mut data = format!("<<<<<<< {}\n", labels.0).into_bytes();
+    for &(oid, ref marker) in &[(ours, "=======\n".to_string()),
+                            (theirs, format!(">>>>>>> {}\n", labels.1))] {
+        data.extend_from_slice(&try!(content(repo, oid)));
+        if data.last() != Some(&b'\n') {
+            data.push(b'\n');
+        }
+        data.extend_from_slice(marker.as_bytes());
+    }
+    Ok(data)
+}
+
+
+/// A directory of a merge result
+struct MergeDir {
+    entries: Vec<(Vec<u8>, Id, FileType)>,
+}
+
+impl MergeDir {
+    fn new(entries: Vec<(Vec<u8>, Id, FileType)>) -> Box<Inode+'static> {
+        Box::new(MergeDir {
+            entries: entries,
+        })
+    }
+}
+
+impl Inode for MergeDir {
+    fn lookup(&mut self, _repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        let name = name.as_os_str().as_bytes();
+        self.entries.binary_search_by(|&(ref entry, _, _)| entry[..].cmp(name))
+            .map(|i| self.entries[i].1)
+            .map_err(|_| libc::ENOENT)
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = self.entries.len() as u64;
+        let subdirs = self.entries.iter()
+            .filter(|&&(_, _, kind)| kind == FileType::Directory)
+            .count();
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2 + subdirs as u32,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, _repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for &(ref name, id, kind) in self.entries.iter().skip(offset as usize) {
+            if add(id, kind, Path::new(<OsStr as OsStrExt>::from_bytes(name))) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// A file made up by a merge, like one with conflict markers or the list of conflicts
+struct MergeFile {
+    data: Vec<u8>,
+}
+
+impl MergeFile {
+    fn new(data: Vec<u8>) -> Box<Inode+'static> {
+        Box::new(MergeFile {
+            data: data,
+        })
+    }
+}
+
+impl Inode for MergeFile {
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = self.data.len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            ..attr
+        })
+    }
+
+    fn open(&mut self, _repo: &git2::Repository, _flags: u32) -> Result<u32, libc::c_int> {
+        Ok(fuse::consts::FOPEN_KEEP_CACHE)
+    }
+
+    fn read(&mut self, _repo: &git2::Repository, offset: u64, size: u32
+           ) -> Result<&[u8], libc::c_int> {
+        let start = cmp::min(offset, self.data.len() as u64) as usize;
+        let end = cmp::min(start as u64 + size as u64, self.data.len() as u64) as usize;
+        Ok(&self.data[start..end])
+    }
+
+    fn release(&mut self, _repo: &git2::Repository) -> Result<(), libc::c_int> {
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Objects that are expected to be missing, from partial and shallow clones.
+
+use fuse::FileType;
+use git2;
+use libc;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use inode;
+use inode::{FileAttr, Id, Inode, Location};
+use trace;
+
+
+/// The extended attribute saying why an object is missing, like "promisor" or "shallow"
+pub const XATTR_MISSING: &'static str = "user.git.missing";
+
+
+/// Explain why objects may be missing from a repository, if they may be: "promisor" for a
+/// partial clone, which can fetch them later, or "shallow" for history cut short.
+pub fn reason(repo: &git2::Repository) -> Option<&'static str> {
+    if is_partial(repo) {
+        Some("promisor")
+    } else if repo.is_shallow() {
+        Some("shallow")
+    } else {
+        None
+    }
+}
+
+/// Check whether a repository is a partial clone, with any promisor remote.
+fn is_partial(repo: &git2::Repository) -> bool {
+    let config = match repo.config() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    if config.get_string("extensions.partialclone").is_ok() {
+        return true;
+    }
+    let remotes = match repo.remotes() {
+        Ok(remotes) => remotes,
+        Err(_) => return false,
+    };
+    remotes.iter()
+        .filter_map(|name| name)
+        .any(|name| config.get_bool(&format!("remote.{}.promisor", name)).unwrap_or(false))
+}
+
+
+/// Copy a missing object into `repo` from the local repository at `promisor`, returning
+/// whether that worked.
+pub fn fetch(repo: &git2::Repository, promisor: &Path, oid: git2::Oid) -> bool {
+    match copy_object(repo, promisor, oid) {
+        Ok(()) => {
+            info!(target: "gitfs::missing", "fetched {} from {}", oid, promisor.display());
+            true
+        },
+        Err(e) => {
+            // NB: This just logs why it failed
+            trace::errno("fetch", &e);
+            false
+        },
+    }
+}
+
+fn copy_object(repo: &git2::Repository, promisor: &Path, oid: git2::Oid
+              ) -> Result<(), git2::Error> {
+    let source = try!(git2::Repository::open(promisor));
+    let source_odb = try!(source.odb());
+    let object = try!(trace::git("read", || source_odb.read(oid)));
+    let odb = try!(repo.odb());
+    try!(trace::git("write", || odb.write(object.kind(), object.data())));
+    Ok(())
+}
+
+
+/// Stands in for an object that's missing, as a file or directory that can't be read
+pub struct Missing {
+    oid: git2::Oid,
+    kind: FileType,
+    reason: &'static str,
+}
+
+impl Missing {
+    /// Create a placeholder for `oid`, which is a directory or file depending on the tree
+    /// entry at `location`.  Submodules are never fetched, so they're simply `ENOENT`.
+    pub fn new(repo: &git2::Repository, oid: git2::Oid, reason: &'static str,
+               location: Option<&Location>) -> Result<Box<Inode+'static>, libc::c_int> {
+        let mode = location.and_then(|location| {
+            if location.path == Path::new("") {
+                return Some(0o040000);
+            }
+            repo.find_tree(location.root).ok()
+                .and_then(|root| root.get_path(&location.path).ok())
+                .map(|entry| entry.filemode())
+        });
+        let kind = match mode {
+            Some(0o040000) => FileType::Directory,
+            Some(0o160000) => return Err(libc::ENOENT),
+            _ => FileType::RegularFile,
+        };
+        Ok(Box::new(Missing {
+            oid: oid,
+            kind: kind,
+            reason: reason,
+        }))
+    }
+
+    /// Log an attempt to use the content, which isn't there.
+    fn missing(&self) -> libc::c_int {
+        info!(target: "gitfs::missing", "{} is missing ({})", self.oid, self.reason);
+        libc::ENODATA
+    }
+}
+
+impl Inode for Missing {
+    fn lookup(&mut self, _repo: &git2::Repository, _name: &Path
+             ) -> Result<Id, libc::c_int> {
+        match self.kind {
+            FileType::Directory => Err(self.missing()),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let (perm, nlink) = match self.kind {
+            FileType::Directory => (0o755, 2),
+            _ => (0o644, attr.nlink),
+        };
+        Ok(FileAttr {
+            kind: self.kind,
+            perm: perm,
+            nlink: nlink,
+            ..attr
+        })
+    }
+
+    fn open(&mut self, _repo: &git2::Repository, _flags: u32) -> Result<u32, libc::c_int> {
+        match self.kind {
+            FileType::Directory => Err(libc::EISDIR),
+            _ => Err(self.missing()),
+        }
+    }
+
+    fn listxattr(&mut self, _repo: &git2::Repository) -> Result<Vec<OsString>, libc::c_int> {
+        let mut names = inode::object_listxattr();
+        names.push(OsString::from(XATTR_MISSING));
+        Ok(names)
+    }
+
+    fn getxattr(&mut self, _repo: &git2::Repository, name: &OsStr
+               ) -> Result<Vec<u8>, libc::c_int> {
+        if name == OsStr::new(XATTR_MISSING) {
+            return Ok(self.reason.as_bytes().to_vec());
+        }
+        let kind = match self.kind {
+            FileType::Directory => git2::ObjectType::Tree,
+            _ => git2::ObjectType::Blob,
+        };
+        inode::object_getxattr(self.oid, kind, name)
+    }
+
+    fn readdir<'a>(&'a mut self, _repo: &git2::Repository, _offset: u64,
+               _add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        match self.kind {
+            FileType::Directory => Err(self.missing()),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn is_placeholder(&self) -> bool {
+        true
+    }
+}
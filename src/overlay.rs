@@ -0,0 +1,232 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Union views of several revisions, stacked like overlayfs layers.
+
+use fuse::FileType;
+use git2;
+use libc;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use filter::Filter;
+use inode;
+use inode::{FileAttr, Id, Inode};
+
+
+/// The extended attribute of the name of the layer an entry was found in
+pub const XATTR_LAYER: &'static str = "user.git.layer";
+
+const DIR_MODE: i32 = 0o040000;
+
+
+/// One revision of an overlay
+pub struct Layer {
+    pub name: String,
+    /// The commit of this layer, if the revision was one
+    pub commit: Option<git2::Oid>,
+    pub tree: git2::Oid,
+    /// The tree of where this layer forked from the one below it, if they share history.
+    /// Whatever was deleted since then is whited out.
+    fork: Option<git2::Oid>,
+}
+
+/// Resolve the revisions of each layer, named like `(name, revspec)` and lowest first.
+pub fn layers(repo: &git2::Repository, specs: &[(String, String)]
+             ) -> Result<Vec<Layer>, git2::Error> {
+    let mut layers: Vec<Layer> = Vec::with_capacity(specs.len());
+    for &(ref name, ref spec) in specs {
+        let object = try!(repo.revparse_single(spec));
+        let commit = object.as_commit().map(|commit| commit.id());
+        let tree = try!(object.peel(git2::ObjectType::Tree)).id();
+        let fork = match (commit, layers.last().and_then(|below| below.commit)) {
+            (Some(commit), Some(below)) => repo.merge_base(commit, below).ok()
+                .and_then(|base| repo.find_commit(base).ok())
+                .map(|base| base.tree_id()),
+            _ => None,
+        };
+        layers.push(Layer {
+            name: name.clone(),
+            commit: commit,
+            tree: tree,
+            fork: fork,
+        });
+    }
+    Ok(layers)
+}
+
+
+/// An entry as found in one layer
+#[derive(Clone, Copy)]
+pub struct Found {
+    pub layer: usize,
+    oid: git2::Oid,
+    mode: i32,
+    /// The same entry where the layer forked, if it was a directory there too
+    fork: Option<git2::Oid>,
+}
+
+impl Found {
+    fn is_dir(&self) -> bool {
+        self.mode == DIR_MODE
+    }
+}
+
+/// The trees of a directory as found in each layer, topmost first
+type Dirs<'a> = Vec<(Found, git2::Tree<'a>, Option<git2::Tree<'a>>)>;
+
+fn load_dirs<'a>(repo: &'a git2::Repository, found: &[Found]) -> Dirs<'a> {
+    found.iter()
+        .filter(|found| found.is_dir())
+        .filter_map(|&found| repo.find_tree(found.oid).ok().map(|tree| {
+            (found, tree, found.fork.and_then(|fork| repo.find_tree(fork).ok()))
+        }))
+        .collect()
+}
+
+/// Find `name` within a directory in each layer, topmost first.  Only directories are merged,
+/// so anything else hides whatever is beneath it, and so does a layer that deleted the name.
+fn descend(dirs: &Dirs, name: &Path) -> Vec<Found> {
+    let mut found = Vec::new();
+    for &(dir, ref tree, ref fork) in dirs {
+        let fork_entry = fork.as_ref().and_then(|fork| fork.get_path(name).ok());
+        match tree.get_path(name) {
+            Ok(entry) => {
+                let entry = Found {
+                    layer: dir.layer,
+                    oid: entry.id(),
+                    mode: entry.filemode(),
+                    fork: fork_entry.and_then(|e| if e.filemode() == DIR_MODE {
+                        Some(e.id())
+                    } else {
+                        None
+                    }),
+                };
+                if !found.is_empty() && !entry.is_dir() {
+                    break;
+                }
+                found.push(entry);
+                if !entry.is_dir() {
+                    break;
+                }
+            },
+            // Whatever this layer deleted since it forked is whited out beneath it
+            Err(_) => if fork_entry.is_some() {
+                break;
+            },
+        }
+    }
+    found
+}
+
+/// Find `path` in each layer, topmost first, or nothing if it's whited out.
+pub fn find(repo: &git2::Repository, layers: &[Layer], path: &Path) -> Vec<Found> {
+    let mut found: Vec<_> = layers.iter().enumerate().rev().map(|(i, layer)| Found {
+        layer: i,
+        oid: layer.tree,
+        mode: DIR_MODE,
+        fork: layer.fork,
+    }).collect();
+    for component in path.components() {
+        let dirs = load_dirs(repo, &found);
+        found = descend(&dirs, Path::new(component.as_os_str()));
+    }
+    found
+}
+
+
+/// A directory merged from the same directory of several layers, or the root of them all
+pub struct OverlayDir {
+    entries: Vec<(Vec<u8>, Id, FileType)>,
+    subdirs: u32,
+}
+
+impl OverlayDir {
+    /// Merge the directories `found` at `path`, leaving out whatever `filter` hides, if any.
+    pub fn new(repo: &git2::Repository, found: &[Found], path: &Path,
+               filter: Option<&Arc<Filter>>) -> Result<Box<Inode+'static>, libc::c_int> {
+        let dirs = load_dirs(repo, found);
+        if dirs.is_empty() {
+            return Err(libc::ENOENT);
+        }
+
+        // NB: Entries are kept sorted by name, to be searched by lookup
+        let mut names = BTreeSet::new();
+        for &(_, ref tree, _) in &dirs {
+            for entry in tree.iter() {
+                names.insert(entry.name_bytes().to_vec());
+            }
+        }
+
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let found = descend(&dirs, Path::new(<OsStr as OsStrExt>::from_bytes(&name)));
+            let top = match found.first() {
+                Some(&top) => top,
+                None => continue,
+            };
+            let kind = match top.mode & 0o170000 {
+                0o040000 => FileType::Directory,
+                0o160000 => FileType::CharDevice, /* something weird?!? unknown... */
+                _ => FileType::RegularFile,
+            };
+            if let Some(filter) = filter {
+                let path = path.join(<OsStr as OsStrExt>::from_bytes(&name));
+                if !filter.allows(&path, top.is_dir()) {
+                    continue;
+                }
+            }
+            // Directories only need merging if more than one layer has them
+            let id = if found.len() > 1 { Id::Overlay(top.oid) } else { Id::Oid(top.oid) };
+            entries.push((name, id, kind));
+        }
+
+        let subdirs = entries.iter().filter(|&&(_, _, kind)| kind == FileType::Directory).count();
+        Ok(Box::new(OverlayDir {
+            entries: entries,
+            subdirs: subdirs as u32,
+        }))
+    }
+}
+
+impl Inode for OverlayDir {
+    fn lookup(&mut self, _repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        let name = name.as_os_str().as_bytes();
+        self.entries.binary_search_by(|&(ref entry, _, _)| entry[..].cmp(name))
+            .map(|i| self.entries[i].1)
+            .map_err(|_| libc::ENOENT)
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = self.entries.len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2 + self.subdirs,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, _repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for &(ref name, id, kind) in self.entries.iter().skip(offset as usize) {
+            if add(id, kind, Path::new(<OsStr as OsStrExt>::from_bytes(name))) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
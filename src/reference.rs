@@ -9,27 +9,130 @@
 use fuse::FileType;
 use git2;
 use libc;
-use std::collections::hash_map;
+use std::collections::{btree_map, btree_set, hash_map};
 use std::default::Default;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 
 use inode;
+use trace;
 
 
 /// Represents a virtual directory in reference paths
 /// (e.g. `refs/heads/master` needs intermediate `refs/` and `refs/heads/`)
 pub struct RefDir {
-    entries: hash_map::HashMap<PathBuf, inode::Id>,
+    // NB: Kept in order, so offsets still mean the same after references are reread
+    entries: btree_map::BTreeMap<PathBuf, inode::Id>,
 }
 
 impl RefDir {
-    pub fn new() -> Box<inode::Inode+'static> {
+    pub fn new(entries: btree_map::BTreeMap<PathBuf, inode::Id>) -> Box<inode::Inode+'static> {
         Box::new(RefDir {
-            entries: Default::default(),
+            entries: entries,
         })
     }
 }
 
+
+/// Collects slash-separated reference names, to be built into one level of RefDir, with
+/// its subdirectories read separately
+#[derive(Default)]
+pub struct RefTree {
+    refs: hash_map::HashMap<PathBuf, git2::Oid>,
+    dirs: btree_set::BTreeSet<OsString>,
+}
+
+impl RefTree {
+    /// Collect all references whose names start with `prefix`, named by the remainder.
+    ///
+    /// Symbolic references are resolved, and tags are peeled to the object they point to.
+    pub fn from_prefix(repo: &git2::Repository, prefix: &str) -> RefTree {
+        let mut tree = RefTree::default();
+        if let Ok(references) = trace::git("references", || repo.references()) {
+            for reference in references.filter_map(|r| r.ok()) {
+                let name = match reference.name() {
+                    Some(name) if name.starts_with(prefix) => &name[prefix.len()..],
+                    _ => continue,
+                };
+                if let Some(oid) = peel(repo, &reference) {
+                    tree.insert(Path::new(name), oid);
+                }
+            }
+        }
+        tree
+    }
+
+    /// Add a reference by name, or the intermediate directory it's in.
+    pub fn insert(&mut self, name: &Path, oid: git2::Oid) {
+        let mut components = name.iter();
+        let first = match components.next() {
+            Some(first) => first,
+            None => return,
+        };
+        if components.as_path() == Path::new("") {
+            self.refs.insert(PathBuf::from(first), oid);
+        } else {
+            self.dirs.insert(first.to_os_string());
+        }
+    }
+
+    /// Get the entries of a RefDir for this tree.  Each subdirectory is passed to `add` by
+    /// name, which must assign it an Id, to be read on its own.
+    pub fn into_entries(self, add: &mut FnMut(&OsStr) -> inode::Id)
+                        -> btree_map::BTreeMap<PathBuf, inode::Id> {
+        let mut entries: btree_map::BTreeMap<_, _> = self.refs.into_iter()
+            .map(|(name, oid)| (name, inode::Id::Oid(oid)))
+            .collect();
+        for name in self.dirs {
+            let id = add(&name);
+            entries.insert(PathBuf::from(name), id);
+        }
+        entries
+    }
+}
+
+
+/// Escape a reference name to fit in a single file name, with `%2F` for each slash and `%25`
+/// for each percent sign.
+pub fn escape(name: &str) -> String {
+    name.replace('%', "%25").replace('/', "%2F")
+}
+
+/// Undo `escape`, leaving any other `%` as it is.
+pub fn unescape(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(i) = rest.find('%') {
+        unescaped.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with("%2F") || rest.starts_with("%2f") {
+            unescaped.push('/');
+            rest = &rest[3..];
+        } else if rest.starts_with("%25") {
+            unescaped.push('%');
+            rest = &rest[3..];
+        } else {
+            unescaped.push('%');
+            rest = &rest[1..];
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Resolve a reference all the way to a commit, or a tree if it's not a commit at all.
+pub fn peel(repo: &git2::Repository, reference: &git2::Reference) -> Option<git2::Oid> {
+    reference.resolve().ok()
+        .and_then(|reference| reference.target())
+        .and_then(|oid| repo.find_object(oid, None).ok())
+        .and_then(|object| {
+            object.peel(git2::ObjectType::Commit)
+                .or_else(|_| object.peel(git2::ObjectType::Tree))
+                .ok()
+        })
+        .map(|object| object.id())
+}
+
 impl inode::Inode for RefDir {
     fn lookup(&mut self, _repo: &git2::Repository, name: &Path
               ) -> Result<inode::Id, libc::c_int> {
@@ -44,6 +147,7 @@ impl inode::Inode for RefDir {
             blocks: inode::st_blocks(size),
             kind: FileType::Directory,
             perm: 0o755,
+            nlink: 2 + size as u32, // every entry is a directory
             ..attr
         })
     }
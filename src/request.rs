@@ -0,0 +1,87 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Requests and replies as `GitFS::handle` sees them, apart from `fuse`'s own objects, so
+//! that what a mount would answer can be checked without one.
+
+use fuse::{FileAttr, FileType};
+use libc;
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::path::Path;
+use time;
+
+use vfs::Statfs;
+
+
+/// A request from the kernel, with just the arguments that GitFS uses.
+pub enum Request<'a> {
+    /// Look up a name in a parent directory.
+    Lookup(u64, &'a Path),
+    /// Get an inode's attributes.
+    Getattr(u64),
+    /// Open an inode with `O_*` flags.
+    Open(u64, u32),
+    /// Read up to a size at an offset of an opened inode.
+    Read(u64, u64, u32),
+    /// Release an opened inode.
+    Release(u64),
+    /// List a directory from an offset, adding entries to a reply buffer like
+    /// `fuse::ReplyDirectory::add`, which returns true once it's full.
+    Readdir(u64, u64, &'a mut FnMut(u64, u64, FileType, &Path) -> bool),
+    /// Get the totals of the filesystem, as asked of an inode.
+    Statfs(u64),
+    /// Get an extended attribute, into a buffer of a size, or 0 to ask how big it must be.
+    Getxattr(u64, &'a OsStr, u32),
+    /// List extended attributes, into a buffer of a size, or 0 to ask how big it must be.
+    Listxattr(u64, u32),
+    /// Check an access mask for a uid and gid.
+    Access(u64, u32, u32, u32),
+    /// Change the size of an inode, if given, without changing its mode or owner.
+    Setattr(u64, Option<u64>),
+    /// Write data at an offset of an opened inode.
+    Write(u64, u64, &'a [u8]),
+    /// Anything that would modify the filesystem, by the name of the operation and the inode
+    /// it was asked of.
+    Modify(&'static str, u64),
+}
+
+
+/// The reply to a request, as it will be sent to the kernel.
+#[derive(Debug)]
+pub enum Reply<'a> {
+    /// Fail with an errno.
+    Error(libc::c_int),
+    /// An entry that was looked up, how long it may be cached, and its generation.  A zero
+    /// inode is a negative entry, caching that the name is missing.
+    Entry(time::Timespec, FileAttr, u64),
+    /// An inode's attributes, and how long they may be cached.
+    Attr(time::Timespec, FileAttr),
+    /// An opened file, with `FOPEN_*` flags for how it should be cached.
+    Opened(u32),
+    /// Data that was read, or an extended attribute or list of them.
+    Data(Cow<'a, [u8]>),
+    /// How big a buffer an extended attribute or list of them needs.
+    Size(u32),
+    /// How much of the data was written.
+    Written(u32),
+    /// The totals of the filesystem.
+    Statfs(Statfs),
+    /// Success, with nothing more to say.
+    Ok,
+}
+
+impl<'a> Reply<'a> {
+    /// The errno to reply with instead, when this isn't the kind of reply a request expects.
+    pub fn errno(&self) -> libc::c_int {
+        match *self {
+            Reply::Error(rc) => rc,
+            _ => libc::EIO,
+        }
+    }
+}
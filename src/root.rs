@@ -13,56 +13,75 @@ use std::path::Path;
 
 use inode;
 use inode::{FileAttr, Id, Inode};
+use trace;
 
-/// The root of the filesystem, currently just revealing HEAD and refs/
+/// The root of the filesystem, revealing HEAD and a fixed set of directories,
+/// like the raw refs/, friendlier views of branches/, tags/ and remotes/, the
+/// HEADs of linked worktrees/, and previews of a merge/
 pub struct Root {
-    head: Id,
-    refs: Id,
+    dirs: Vec<(&'static str, Id)>,
 }
 
 impl Root {
-    pub fn new(head: Id, refs: Id) -> Box<Inode+'static> {
+    pub fn new(dirs: Vec<(&'static str, Id)>) -> Box<Inode+'static> {
         Box::new(Root {
-            head: head,
-            refs: refs,
+            dirs: dirs,
         })
     }
+
+    /// Find the current commit of HEAD, which is `ENOENT` if it's unborn.
+    fn head(repo: &git2::Repository) -> Result<Id, libc::c_int> {
+        let head = try!(trace::git_errno("head", || repo.head()));
+        head.target().map(|oid| Id::Oid(oid)).ok_or(libc::ENOENT)
+    }
 }
 
 impl Inode for Root {
     fn lookup(&mut self, repo: &git2::Repository, name: &Path
              ) -> Result<Id, libc::c_int> {
         if name == Path::new("HEAD") {
-            repo.head().ok()
-                .and_then(|head| head.target())
-                .map(|oid| Id::Oid(oid))
+            Root::head(repo)
         }
-        else if name == Path::new("refs") {
-            Some(self.refs)
+        else {
+            self.dirs.iter()
+                .find(|&&(dir, _)| name == Path::new(dir))
+                .map(|&(_, id)| id)
+                .ok_or(libc::ENOENT)
         }
-        else { None }.ok_or(libc::ENOENT)
     }
 
-    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+    fn getattr(&mut self, repo: &git2::Repository, attr: FileAttr
               ) -> Result<FileAttr, libc::c_int> {
-        let size = 2; // just HEAD and refs/
+        // HEAD, unless it's unborn, and the other directories
+        let head = if Root::head(repo).is_ok() { 1 } else { 0 };
+        let size = head + self.dirs.len() as u64;
         Ok(FileAttr {
             size: size,
             blocks: inode::st_blocks(size),
             kind: FileType::Directory,
             perm: 0o755,
+            nlink: 2 + size as u32,
             ..attr
         })
     }
 
-    fn readdir<'a>(&mut self, _repo: &git2::Repository, offset: u64,
+    fn readdir<'a>(&mut self, repo: &git2::Repository, offset: u64,
                mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
               ) -> Result<(), libc::c_int> {
-        if offset == 0 {
-            add(self.head, FileType::Directory, &Path::new("HEAD"));
-        }
-        if offset <= 1 {
-            add(self.refs, FileType::Directory, &Path::new("refs"));
+        // NB: Offsets count what was listed, so an unborn HEAD doesn't take a place in them
+        let skip = match Root::head(repo) {
+            Ok(head) => {
+                if offset == 0 && add(head, FileType::Directory, &Path::new("HEAD")) {
+                    return Ok(());
+                }
+                offset.saturating_sub(1)
+            },
+            Err(_) => offset,
+        };
+        for &(dir, id) in self.dirs.iter().skip(skip as usize) {
+            if add(id, FileType::Directory, &Path::new(dir)) {
+                break;
+            }
         }
         Ok(())
     }
@@ -0,0 +1,84 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use fuse::FileType;
+use git2;
+use libc;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+
+use inode;
+use inode::{FileAttr, Id, Inode};
+
+
+/// Find all of the repositories directly within `dir`, sorted by name.
+pub fn find_repositories(dir: &Path) -> Result<Vec<(OsString, git2::Repository)>, git2::Error> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| {
+        git2::Error::from_str(&format!("{}: {}", dir.display(), e))
+    }));
+
+    let mut repos: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            // Anything that isn't a repository is simply skipped
+            git2::Repository::open(entry.path()).ok()
+                .map(|repo| (entry.file_name(), repo))
+        })
+        .collect();
+    repos.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(repos)
+}
+
+
+/// The root of a multi-repository mount, with a directory for each repository
+pub struct RepoList {
+    entries: Vec<(OsString, Id)>,
+}
+
+impl RepoList {
+    pub fn new(entries: Vec<(OsString, Id)>) -> Box<Inode+'static> {
+        Box::new(RepoList {
+            entries: entries,
+        })
+    }
+}
+
+impl Inode for RepoList {
+    fn lookup(&mut self, _repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        self.entries.iter()
+            .find(|&&(ref entry, _)| name == Path::new(entry))
+            .map(|&(_, id)| id)
+            .ok_or(libc::ENOENT)
+    }
+
+    fn getattr(&mut self, _repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = self.entries.len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2 + size as u32,
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, _repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for &(ref name, id) in self.entries.iter().skip(offset as usize) {
+            if add(id, FileType::Directory, Path::new(name)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
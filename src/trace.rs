@@ -0,0 +1,143 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Instrumentation of filesystem operations and git2 calls.
+//!
+//! Each finished operation is logged through the `log` crate, under the target `gitfs::op`
+//! at trace level, as `op=NAME ino=INO time=MICROSECONDS errno=ERRNO`.  Calls into git2 are
+//! logged under `gitfs::git2` as `call=NAME time=MICROSECONDS`, at debug level if they fail.
+//! The same events are also USDT probes `gitfs:op_done` and `gitfs:git2_done`, with the
+//! `probe` feature.
+//!
+//! Failures of git2 that become errno values are mapped centrally by `errno`, which also logs
+//! git2's own message for them under `gitfs::git2`.
+
+use git2;
+use libc;
+use log::LogLevel;
+use time;
+
+
+/// Times one filesystem operation, to be logged when it finishes
+pub struct Span {
+    op: &'static str,
+    ino: u64,
+    start: u64,
+}
+
+impl Span {
+    /// Start timing an operation on an inode.
+    pub fn new(op: &'static str, ino: u64) -> Span {
+        Span {
+            op: op,
+            ino: ino,
+            start: time::precise_time_ns(),
+        }
+    }
+
+    /// Log the result of the operation, passing it through.
+    pub fn finish<T>(self, result: Result<T, libc::c_int>) -> Result<T, libc::c_int> {
+        let micros = (time::precise_time_ns() - self.start) / 1000;
+        let errno = match result {
+            Ok(_) => 0,
+            Err(rc) => rc,
+        };
+        fire_op_done(self.op, self.ino, micros, errno);
+        trace!(target: "gitfs::op", "op={} ino={} time={}us errno={}",
+               self.op, self.ino, micros, errno);
+        result
+    }
+}
+
+
+/// Log an operation that's refused because the filesystem is read-only, returning `EROFS`.
+pub fn read_only(op: &'static str, ino: u64) -> libc::c_int {
+    Span::new(op, ino).finish::<()>(Err(libc::EROFS)).unwrap_err()
+}
+
+
+/// Call into git2, logging how long it took and whether it failed.
+pub fn git<T, F>(call: &'static str, f: F) -> Result<T, git2::Error>
+    where F: FnOnce() -> Result<T, git2::Error>
+{
+    let start = time::precise_time_ns();
+    let result = f();
+    let micros = (time::precise_time_ns() - start) / 1000;
+    fire_git2_done(call, micros, result.is_ok());
+    match result {
+        Ok(_) => trace!(target: "gitfs::git2", "call={} time={}us", call, micros),
+        Err(ref e) => debug!(target: "gitfs::git2", "call={} time={}us error={:?}",
+                             call, micros, e.message()),
+    }
+    result
+}
+
+
+/// Call into git2 like `git`, mapping any failure to an errno with `errno`.
+pub fn git_errno<T, F>(call: &'static str, f: F) -> Result<T, libc::c_int>
+    where F: FnOnce() -> Result<T, git2::Error>
+{
+    git(call, f).map_err(|e| errno(call, &e))
+}
+
+
+/// Map a git2 error to the closest errno, logging the message git2 gave for it.
+///
+/// Anything missing is `ENOENT`, logged at info level since it may mean a missing object,
+/// except for an unborn branch which is routine.  Other failures are logged as warnings,
+/// and anything without a closer match, like a corrupt object, is `EIO`.
+pub fn errno(call: &str, err: &git2::Error) -> libc::c_int {
+    use git2::{ErrorClass, ErrorCode};
+
+    let errno = match err.code() {
+        ErrorCode::NotFound | ErrorCode::UnbornBranch => libc::ENOENT,
+        ErrorCode::Exists => libc::EEXIST,
+        ErrorCode::Ambiguous | ErrorCode::InvalidSpec | ErrorCode::Peel => libc::EINVAL,
+        ErrorCode::BufSize => libc::ERANGE,
+        ErrorCode::Locked => libc::EBUSY,
+        ErrorCode::Auth | ErrorCode::Certificate => libc::EACCES,
+        _ => match err.class() {
+            ErrorClass::NoMemory => libc::ENOMEM,
+            ErrorClass::Invalid => libc::EINVAL,
+            ErrorClass::Net | ErrorClass::Ssl | ErrorClass::Ssh => libc::EHOSTUNREACH,
+            _ => libc::EIO,
+        },
+    };
+
+    let level = match err.code() {
+        ErrorCode::UnbornBranch => LogLevel::Debug,
+        ErrorCode::NotFound => LogLevel::Info,
+        _ => LogLevel::Warn,
+    };
+    log!(target: "gitfs::git2", level, "call={} errno={} class={:?} code={:?}: {}",
+         call, errno, err.class(), err.code(), err.message());
+    errno
+}
+
+
+// NB: The probes need C strings, which are only worth making if the probes exist.
+
+#[cfg(feature="probe")]
+fn fire_op_done(op: &str, ino: u64, micros: u64, errno: libc::c_int) {
+    if let Ok(op) = ::std::ffi::CString::new(op) {
+        probe!(gitfs, op_done, op.as_ptr(), ino, micros, errno);
+    }
+}
+
+#[cfg(not(feature="probe"))]
+fn fire_op_done(_op: &str, _ino: u64, _micros: u64, _errno: libc::c_int) {}
+
+#[cfg(feature="probe")]
+fn fire_git2_done(call: &str, micros: u64, ok: bool) {
+    if let Ok(call) = ::std::ffi::CString::new(call) {
+        probe!(gitfs, git2_done, call.as_ptr(), micros, ok);
+    }
+}
+
+#[cfg(not(feature="probe"))]
+fn fire_git2_done(_call: &str, _micros: u64, _ok: bool) {}
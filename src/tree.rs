@@ -9,31 +9,74 @@
 use fuse::FileType;
 use git2;
 use libc;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use filter::Filter;
 use inode;
 use inode::{FileAttr, Id, Inode};
+use trace;
 
 /// Git trees are represented as directories
 // FIXME needs context, e.g. permissions from TreeEntry and timestamps from Commit
 pub struct Tree {
     oid: git2::Oid,
     size: u64,
+    subdirs: u32,
+    /// The filter hiding some entries, and the path of this tree within its root
+    filter: Option<(Arc<Filter>, PathBuf)>,
+    /// The index of each entry that isn't hidden, if any are
+    visible: Option<Vec<usize>>,
 }
 
 impl Tree {
-    pub fn new(tree: git2::Tree) -> Box<Inode+'static> {
+    /// Create a tree found at `path` within its root, showing only the entries that `filter`
+    /// allows, if any.
+    pub fn new(tree: git2::Tree, path: &Path, filter: Option<&Arc<Filter>>
+              ) -> Box<Inode+'static> {
+        let filter = filter.map(|filter| (filter.clone(), path.to_path_buf()));
+        let visible = filter.as_ref().map(|&(ref filter, ref path)| {
+            tree.iter().enumerate()
+                .filter(|&(_, ref e)| filter.allows(&path.join(entry_name(e)), is_tree(e)))
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>()
+        });
+        let subdirs = match visible {
+            Some(ref visible) => visible.iter()
+                .filter(|&&i| tree.get(i).map_or(false, |e| is_tree(&e)))
+                .count(),
+            None => tree.iter().filter(is_tree).count(),
+        };
         Box::new(Tree {
             oid: tree.id(),
-            size: tree.len() as u64,
+            size: visible.as_ref().map_or(tree.len(), |visible| visible.len()) as u64,
+            subdirs: subdirs as u32,
+            filter: filter,
+            visible: visible,
         })
     }
 
     fn tree<'a>(&self, repo: &'a git2::Repository) -> Result<git2::Tree<'a>, libc::c_int> {
-        repo.find_tree(self.oid).map_err(|_| libc::EINVAL)
+        trace::git_errno("find_tree", || repo.find_tree(self.oid))
     }
+
+    /// Check whether the filter hides entry `e`, found by `name`.
+    fn hides(&self, name: &Path, e: &git2::TreeEntry) -> bool {
+        match self.filter {
+            Some((ref filter, ref path)) => !filter.allows(&path.join(name), is_tree(e)),
+            None => false,
+        }
+    }
+}
+
+fn entry_name<'a>(e: &'a git2::TreeEntry) -> &'a Path {
+    Path::new(<OsStr as OsStrExt>::from_bytes(e.name_bytes()))
+}
+
+fn is_tree(e: &git2::TreeEntry) -> bool {
+    e.kind() == Some(git2::ObjectType::Tree)
 }
 
 impl Inode for Tree {
@@ -41,8 +84,11 @@ impl Inode for Tree {
               ) -> Result<Id, libc::c_int> {
         self.tree(repo).and_then(|tree| {
             match tree.get_path(name) {
+                Ok(ref e) if self.hides(name, e) => Err(libc::ENOENT),
                 Ok(e) => Ok(Id::Oid(e.id())),
-                Err(_) => Err(libc::ENOENT),
+                // NB: Missing names are routine, not worth logging
+                Err(ref e) if e.code() == git2::ErrorCode::NotFound => Err(libc::ENOENT),
+                Err(e) => Err(trace::errno("get_path", &e)),
             }
         })
     }
@@ -54,17 +100,29 @@ impl Inode for Tree {
             blocks: inode::st_blocks(self.size),
             kind: FileType::Directory,
             perm: 0o755,
+            nlink: 2 + self.subdirs,
             ..attr
         })
     }
 
+    fn listxattr(&mut self, _repo: &git2::Repository) -> Result<Vec<OsString>, libc::c_int> {
+        Ok(inode::object_listxattr())
+    }
+
+    fn getxattr(&mut self, _repo: &git2::Repository, name: &OsStr
+               ) -> Result<Vec<u8>, libc::c_int> {
+        inode::object_getxattr(self.oid, git2::ObjectType::Tree, name)
+    }
+
     fn readdir<'a>(&'a mut self, repo: &git2::Repository, offset: u64,
                mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
               ) -> Result<(), libc::c_int> {
         let len = self.size;
+        let visible = self.visible.as_ref();
         self.tree(repo).map(|tree| {
             for i in offset..len {
-                let e = match tree.get(i as usize) {
+                let i = visible.map_or(i as usize, |visible| visible[i as usize]);
+                let e = match tree.get(i) {
                     Some(e) => e,
                     None => continue,
                 };
@@ -73,8 +131,7 @@ impl Inode for Tree {
                     Some(git2::ObjectType::Blob) => FileType::RegularFile,
                     _ => FileType::CharDevice, /* something weird?!? unknown... */
                 };
-                let os_path = <OsStr as OsStrExt>::from_bytes(e.name_bytes());
-                if add(Id::Oid(e.id()), kind, Path::new(os_path)) {
+                if add(Id::Oid(e.id()), kind, entry_name(&e)) {
                     break;
                 }
             }
@@ -0,0 +1,1193 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use fuse::{self, FileAttr, FileType};
+use git2;
+use libc;
+use std::cmp;
+use std::collections::{hash_map, hash_set};
+use std::default::Default;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use time;
+
+use {LfsPolicy, Options, RootView};
+use archive;
+use attr;
+use blob;
+use cache::{BlobCache, Key, SharedCache};
+use control;
+use filter::Filter;
+use inode;
+use inode::{Id, Inode, InodeContainer, InodeMapper, Location};
+use lfs;
+use merge;
+use overlay;
+use reference;
+use root;
+use scan;
+use trace;
+use worktree;
+
+
+/// The inode number of the root directory.
+pub const ROOT_INO: u64 = 1;
+
+/// The extended attribute of an entry's mode in its tree, in octal like `100644`
+const XATTR_MODE: &'static str = "user.git.mode";
+/// The extended attribute of the commit through which an entry was reached
+const XATTR_COMMIT: &'static str = "user.git.commit";
+
+
+/// Filesystem totals, as reported by `statfs`
+#[derive(Clone, Copy, Debug)]
+pub struct Statfs {
+    /// The number of blocks used by the repositories' object stores
+    pub blocks: u64,
+    /// The number of inodes assigned so far
+    pub files: u64,
+    /// The size of a block, which is also the unit of `FileAttr::blocks`
+    pub bsize: u32,
+    /// The maximum length of a file name
+    pub namelen: u32,
+}
+
+
+/// A virtual filesystem of Git objects, independent of any FUSE mount.
+///
+/// Inodes are numbered like they would be in a mount, starting from `ROOT_INO`, and each
+/// operation returns the same results and errno values that the mount would give.
+pub struct Vfs {
+    source: PathBuf,
+    repos: Vec<git2::Repository>,
+    names: Option<Vec<OsString>>,
+    root: Option<git2::Oid>,
+    options: Options,
+    ready: bool,
+    epoch: time::Timespec,
+    uid: u32,
+    gid: u32,
+    mapper: InodeMapper,
+    inodes: InodeContainer,
+    locations: hash_map::HashMap<u64, Location>,
+    object_blocks: Option<u64>,
+    /// How many times each blob appears within a root tree, when sharing blobs as hard links
+    links: hash_map::HashMap<git2::Oid, hash_map::HashMap<git2::Oid, u32>>,
+    cache: SharedCache,
+    /// The `.gitattributes` rules of trees that checkout conversions were found in
+    attr_rules: attr::RulesCache,
+    /// How many times each inode is currently open
+    open_files: hash_map::HashMap<u64, u32>,
+    /// The inodes standing in for objects missing from a partial or shallow clone
+    placeholders: hash_set::HashSet<u64>,
+    stats: control::Stats,
+    control_dir: u64,
+    controls: hash_map::HashMap<u64, control::File>,
+    /// The rendered content of each open control file
+    control_data: hash_map::HashMap<u64, Vec<u8>>,
+    commands: Arc<control::Commands>,
+    /// The directories of references in every namespace, each read when it's used
+    ref_dirs: hash_map::HashMap<u64, RefSource>,
+    /// The directories of linked worktrees, in every namespace
+    worktree_dirs: hash_set::HashSet<u64>,
+    /// The name of the worktree that each of their HEADs was found as
+    worktrees: hash_map::HashMap<u64, String>,
+    /// The directories of merge previews, in every namespace
+    merge_dirs: hash_set::HashSet<u64>,
+    /// The name that each merge preview was found as, like `ours...theirs`
+    merges: hash_map::HashMap<u64, String>,
+    /// What the trees of each namespace hide, if anything
+    filters: Vec<Option<Arc<Filter>>>,
+    /// The revisions stacked at the root, lowest first, if it's an overlay
+    layers: Vec<overlay::Layer>,
+}
+
+impl Vfs {
+    /// Create a Vfs referencing the given GIT_DIR, with the given view at its root.
+    ///
+    /// A `RootView::Revision` or `RootView::Overlay` is resolved right away, so the tree won't
+    /// change even if a revspec later names something else, like a moving branch.
+    pub fn new<P: AsRef<Path>>(git_dir: &P, root: RootView) -> Result<Vfs, git2::Error> {
+        let repo = try!(git2::Repository::open(git_dir.as_ref()));
+        let mut layers = Vec::new();
+        let root = match root {
+            RootView::Repository => None,
+            RootView::Revision(ref spec) => {
+                let object = try!(repo.revparse_single(spec));
+                Some(try!(object.peel(git2::ObjectType::Tree)).id())
+            },
+            RootView::Overlay(ref specs) => {
+                layers = try!(overlay::layers(&repo, specs));
+                None
+            },
+        };
+        let source = repo.path().to_path_buf();
+        let mut vfs = Vfs::with_repos(source, vec![repo], None, root);
+        vfs.layers = layers;
+        Ok(vfs)
+    }
+
+    /// Create a Vfs serving every repository found directly within `dir`, each as a top-level
+    /// directory named like its entry in `dir`, with the usual HEAD and refs/ view inside.
+    pub fn scan<P: AsRef<Path>>(dir: &P) -> Result<Vfs, git2::Error> {
+        let found = try!(scan::find_repositories(dir.as_ref()));
+        if found.is_empty() {
+            let msg = format!("{}: no repositories found", dir.as_ref().display());
+            return Err(git2::Error::from_str(&msg));
+        }
+
+        let (names, repos) = found.into_iter().unzip();
+        Ok(Vfs::with_repos(dir.as_ref().to_path_buf(), repos, Some(names), None))
+    }
+
+    fn with_repos(source: PathBuf, repos: Vec<git2::Repository>, names: Option<Vec<OsString>>,
+                  root: Option<git2::Oid>) -> Vfs {
+        Vfs {
+            source: source,
+            repos: repos,
+            names: names,
+            root: root,
+            options: Default::default(),
+            ready: false,
+            epoch: time::get_time(),
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+            mapper: Default::default(),
+            inodes: Default::default(),
+            locations: Default::default(),
+            object_blocks: None,
+            links: Default::default(),
+            cache: BlobCache::shared(Options::default().cache_size),
+            attr_rules: Default::default(),
+            open_files: Default::default(),
+            stats: Default::default(),
+            control_dir: 0,
+            controls: Default::default(),
+            control_data: Default::default(),
+            commands: Default::default(),
+            ref_dirs: Default::default(),
+            worktree_dirs: Default::default(),
+            worktrees: Default::default(),
+            merge_dirs: Default::default(),
+            placeholders: Default::default(),
+            merges: Default::default(),
+            filters: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Get the options to adjust, before the first operation.
+    pub fn options_mut(&mut self) -> &mut Options {
+        &mut self.options
+    }
+
+    /// Get the resolved GIT_DIR, or the scanned directory when serving many repositories.
+    pub fn git_dir(&self) -> &Path {
+        &self.source
+    }
+
+    fn defattr(&self, ino: u64) -> FileAttr {
+        // Shared blobs are linked once for each time they appear in their tree
+        let nlink = match (self.mapper.get_id(ino), self.locations.get(&ino)) {
+            (Some(Id::Oid(oid)), Some(location)) => {
+                self.links.get(&location.root).and_then(|counts| counts.get(&oid)).cloned()
+            },
+            _ => None,
+        };
+        FileAttr {
+            ino: ino,
+            size: 0,
+            blocks: 0,
+            atime: self.epoch,
+            mtime: self.epoch,
+            ctime: self.epoch,
+            crtime: self.epoch,
+            kind: FileType::RegularFile, /* unknown... */
+            perm: 0,
+            nlink: nlink.unwrap_or(1),
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    /// Whether an inode is a Git object, whose entries and attributes never change.  An archive's
+    /// size isn't known until it's first opened, so it only counts once it has been, and a
+    /// missing object may still be fetched.
+    fn immutable(&self, ino: u64) -> bool {
+        match self.mapper.get_id(ino) {
+            Some(Id::Archive(oid, format)) => self.mapper.namespace(ino).map_or(false, |ns| {
+                let key = Key::Archive(self.repos[ns].path().to_path_buf(), oid, format);
+                self.cache.lock().unwrap().archive(&key).is_some()
+            }),
+            Some(_) if self.placeholders.contains(&ino) => false,
+            Some(_) => true,
+            None => ino == ROOT_INO && (self.root.is_some() || !self.layers.is_empty()),
+        }
+    }
+
+    /// Whether an inode is a directory merged from the layers of an overlay.
+    fn is_overlay(&self, ino: u64) -> bool {
+        match self.mapper.get_id(ino) {
+            Some(Id::Overlay(_)) => true,
+            _ => ino == ROOT_INO && !self.layers.is_empty(),
+        }
+    }
+
+    /// Get the name of the overlay layer that a location is in, if any.
+    fn layer_name(&self, location: &Location) -> Option<&str> {
+        self.layers.iter().rev()
+            .find(|layer| layer.tree == location.root)
+            .map(|layer| &layer.name[..])
+    }
+
+    /// How long the attributes of an inode may be cached.
+    pub fn attr_ttl(&self, ino: u64) -> time::Timespec {
+        if self.immutable(ino) { self.options.object_ttl } else { self.options.ref_ttl }
+    }
+
+    /// How long an entry found in `parent` may be cached, along with its attributes.  Objects
+    /// in trees are cached the longest, but not the name of a commit in a reference directory.
+    pub fn entry_ttl(&self, parent: u64, ino: u64) -> time::Timespec {
+        cmp::min(self.attr_ttl(parent), self.attr_ttl(ino))
+    }
+
+    /// How long a name that's missing from `parent` may be cached as missing.
+    pub fn negative_ttl(&self, parent: u64) -> time::Timespec {
+        if self.immutable(parent) { self.options.object_ttl } else { self.options.negative_ttl }
+    }
+
+    /// Create the top-level inode for the repository in namespace `ns`.
+    fn new_root(&mut self, ns: usize) -> Result<Box<Inode+'static>, libc::c_int> {
+        if let Some(oid) = self.root {
+            let location = self.root_location();
+            let repo = &self.repos[ns];
+            return inode::new_inode(repo, Id::Oid(oid), &self.options, location.as_ref(),
+                                    &self.cache, &mut self.attr_rules, self.filters[ns].as_ref());
+        }
+        if !self.layers.is_empty() {
+            let repo = &self.repos[ns];
+            let found = overlay::find(repo, &self.layers, Path::new(""));
+            return overlay::OverlayDir::new(repo, &found, Path::new(""),
+                                            self.filters[ns].as_ref());
+        }
+
+        let refs = self.add_refs(ns, "refs", "refs/");
+        let branches = self.add_refs(ns, "branches", "refs/heads/");
+        let tags = self.add_refs(ns, "tags", "refs/tags/");
+        let remotes = self.add_refs(ns, "remotes", "refs/remotes/");
+
+        let archive_ino = self.mapper.new_ino(ns, Path::new("archive"));
+        self.inodes.insert(archive_ino, archive::ArchiveDir::new());
+
+        let worktrees_ino = self.mapper.new_ino(ns, Path::new("worktrees"));
+        self.inodes.insert(worktrees_ino, worktree::WorktreeDir::new());
+        self.worktree_dirs.insert(worktrees_ino);
+
+        let merge_ino = self.mapper.new_ino(ns, Path::new("merge"));
+        self.inodes.insert(merge_ino, merge::MergeList::new());
+        self.merge_dirs.insert(merge_ino);
+
+        Ok(root::Root::new(vec![
+            ("refs", refs),
+            ("branches", branches),
+            ("tags", tags),
+            ("remotes", remotes),
+            ("archive", Id::Ino(archive_ino)),
+            ("worktrees", Id::Ino(worktrees_ino)),
+            ("merge", Id::Ino(merge_ino)),
+        ]))
+    }
+
+    /// Get the location of a revision's tree mounted as the root, if any, or the top layer of
+    /// an overlay.
+    fn root_location(&self) -> Option<Location> {
+        if let Some(top) = self.layers.last() {
+            return Some(Location {
+                root: top.tree,
+                path: PathBuf::new(),
+                commit: top.commit,
+            });
+        }
+        self.root.map(|oid| Location {
+            root: oid,
+            path: PathBuf::new(),
+            commit: None,
+        })
+    }
+
+    /// Get the location of entry `name` in directory `parent`, if it's a Git object.
+    fn child_location(&self, ns: usize, parent: u64, name: &Path, id: Id) -> Option<Location> {
+        let oid = match id {
+            Id::Oid(oid) | Id::Overlay(oid) => oid,
+            _ => return None,
+        };
+        let repo = &self.repos[ns];
+        match self.locations.get(&parent) {
+            Some(location) => {
+                let mut location = Location {
+                    root: location.root,
+                    path: location.path.join(name),
+                    commit: location.commit,
+                };
+                if self.is_overlay(parent) {
+                    // Entries of a merged directory belong to the topmost layer that has them
+                    let found = overlay::find(repo, &self.layers, &location.path);
+                    if let Some(layer) = found.first().map(|found| &self.layers[found.layer]) {
+                        location.root = layer.tree;
+                        location.commit = layer.commit;
+                    }
+                }
+                Some(location)
+            },
+            None => {
+                inode::peel_tree(repo, oid).map(|root| Location {
+                    root: root,
+                    path: PathBuf::new(),
+                    commit: repo.find_commit(oid).ok().map(|commit| commit.id()),
+                })
+            },
+        }
+    }
+
+    /// Get the root tree within which blobs are shared as hard links, if they are, after
+    /// counting how many times each blob appears there.
+    fn shared_root(&mut self, ns: usize, location: Option<&Location>) -> Option<git2::Oid> {
+        // NB: Blobs aren't shared across the layers of an overlay
+        if !self.options.hardlinks || self.options.checkout || !self.layers.is_empty() {
+            return None;
+        }
+        location.map(|location| {
+            let repo = &self.repos[ns];
+            self.links.entry(location.root).or_insert_with(|| {
+                let mut counts = hash_map::HashMap::new();
+                count_blobs(repo, location.root, &mut counts);
+                counts
+            });
+            location.root
+        })
+    }
+
+    /// Reserve directory `name` of the references in namespace `ns` with the given prefix, to
+    /// be read when it's first used.
+    fn add_refs(&mut self, ns: usize, name: &str, prefix: &str) -> Id {
+        let ino = self.mapper.new_ino(ns, Path::new(name));
+        self.ref_dirs.insert(ino, RefSource {
+            prefix: prefix.to_owned(),
+            path: PathBuf::from(name),
+            read: None,
+        });
+        Id::Ino(ino)
+    }
+
+    /// Read the references of directory `ino`, unless they were read within `ref_ttl`, and
+    /// forget whatever it listed that's gone.  Other directories of references are left alone.
+    fn read_refs(&mut self, ino: u64) -> Result<(), libc::c_int> {
+        let now = time::get_time();
+        let ttl = self.options.ref_ttl - time::Timespec::new(0, 0);
+        let (prefix, path) = match self.ref_dirs.get(&ino) {
+            Some(source) => match source.read {
+                Some(read) if now < read + ttl && self.inodes.contains(ino) => return Ok(()),
+                Some(_) => {
+                    self.stats.refreshes += 1;
+                    (source.prefix.clone(), source.path.clone())
+                },
+                None => (source.prefix.clone(), source.path.clone()),
+            },
+            None => return Ok(()),
+        };
+
+        let ns = try!(self.mapper.namespace(ino).ok_or(libc::ENOENT));
+        let tree = reference::RefTree::from_prefix(&self.repos[ns], &prefix);
+        let entries = {
+            let mapper = &mut self.mapper;
+            let ref_dirs = &mut self.ref_dirs;
+            tree.into_entries(&mut |name| {
+                let path = path.join(name);
+                let ino = mapper.new_ino(ns, &path);
+                ref_dirs.entry(ino).or_insert_with(|| RefSource {
+                    prefix: format!("{}{}/", prefix, name.to_string_lossy()),
+                    path: path,
+                    read: None,
+                });
+                Id::Ino(ino)
+            })
+        };
+
+        // Anything else found here before, like a deleted branch, is gone along with its inodes
+        let mut current = hash_set::HashSet::new();
+        for (name, &id) in &entries {
+            match id {
+                Id::Ino(dir) => { current.insert(dir); },
+                id => { current.extend(self.mapper.find_ino(id, ino, name)); },
+            }
+        }
+        let mut stale = self.mapper.children(ino);
+        stale.extend(self.ref_dirs.iter()
+            .filter(|&(_, source)| source.path.parent() == Some(&path))
+            .map(|(&dir, _)| dir));
+        for child in stale {
+            if !current.contains(&child) {
+                self.forget(child);
+            }
+        }
+
+        self.inodes.insert(ino, reference::RefDir::new(entries));
+        if let Some(source) = self.ref_dirs.get_mut(&ino) {
+            source.read = Some(now);
+        }
+        Ok(())
+    }
+
+    /// Mark every directory of references to be read again when it's next used.
+    fn expire_refs(&mut self) {
+        for source in self.ref_dirs.values_mut() {
+            if source.read.is_some() {
+                source.read = Some(time::Timespec::new(0, 0));
+            }
+        }
+        self.object_blocks = None;
+    }
+
+    /// Forget an inode that's gone from its directory, along with everything found within it,
+    /// except what's still open.
+    fn forget(&mut self, ino: u64) {
+        let gone: hash_set::HashSet<u64> = self.mapper.descendants(ino).into_iter()
+            .filter(|ino| !self.open_files.contains_key(ino))
+            .collect();
+        for &ino in &gone {
+            self.inodes.remove(ino);
+            self.locations.remove(&ino);
+            self.ref_dirs.remove(&ino);
+            self.placeholders.remove(&ino);
+        }
+        self.mapper.forget(&gone);
+    }
+
+    /// Create the root directory, and the top level of each repository within it.
+    fn build_root(&mut self) -> Result<Box<Inode+'static>, libc::c_int> {
+        // NB: The list of scanned repositories doesn't consult any repository itself,
+        // so it's simply filed in the first namespace.
+        match self.names.clone() {
+            None => self.new_root(0),
+            Some(names) => {
+                let mut entries = Vec::with_capacity(names.len());
+                for (ns, name) in names.into_iter().enumerate() {
+                    self.mapper.set_namespace(ns, name.as_bytes());
+                    let ino = self.mapper.new_ino(ns, Path::new(""));
+                    let inode = try!(self.new_root(ns));
+                    self.inodes.insert(ino, inode);
+                    entries.push((name, Id::Ino(ino)));
+                }
+                Ok(scan::RepoList::new(entries))
+            },
+        }
+    }
+
+    /// Create the hidden control directory and its files.
+    fn build_control(&mut self) {
+        let dir = Path::new(control::DIR_NAME);
+        let files = control::FILES.iter().map(|&file| {
+            let ino = self.mapper.new_ino(0, &dir.join(file.name()));
+            self.controls.insert(ino, file);
+            (file, ino)
+        }).collect();
+        self.control_dir = self.mapper.new_ino(0, dir);
+        self.inodes.insert(self.control_dir, control::ControlDir::new(files));
+    }
+
+    /// Set up the root directory.  This happens automatically on the first operation, but
+    /// may be called early to catch any errors.
+    pub fn init(&mut self) -> Result<(), libc::c_int> {
+        if self.ready {
+            return self.run_commands();
+        }
+
+        let root_ino = self.mapper.root_ino();
+        assert_eq!(ROOT_INO, root_ino);
+        self.cache.lock().unwrap().set_limit(self.options.cache_size);
+        self.filters = self.repos.iter()
+            .map(|repo| Filter::new(repo, &self.options).map(Arc::new))
+            .collect();
+
+        let root = try!(self.build_root());
+        self.inodes.insert(root_ino, root);
+        if let Some(location) = self.root_location() {
+            self.locations.insert(root_ino, location);
+        }
+        self.build_control();
+
+        self.ready = true;
+        Ok(())
+    }
+
+    /// Reread all references, rebuilding each directory that lists them when it's next used.
+    /// Inode numbers of directories that still exist are kept.
+    pub fn refresh(&mut self) -> Result<(), libc::c_int> {
+        try!(self.init());
+        self.expire_refs();
+        Ok(())
+    }
+
+    /// Get the commands that other threads may ask for, to be carried out before the next
+    /// operation.
+    pub fn commands(&self) -> Arc<control::Commands> {
+        self.commands.clone()
+    }
+
+    /// Carry out whatever commands are pending.
+    fn run_commands(&mut self) -> Result<(), libc::c_int> {
+        let (refresh, drop_caches) = self.commands.take();
+        if refresh {
+            self.expire_refs();
+        }
+        if drop_caches {
+            try!(self.drop_caches());
+        }
+        Ok(())
+    }
+
+    /// Drop every inode of a Git object that isn't currently open, and all cached blob
+    /// content, to be loaded again the next time it's used.
+    pub fn drop_caches(&mut self) -> Result<(), libc::c_int> {
+        try!(self.init());
+
+        self.stats.cache_drops += 1;
+        for ino in self.inodes.inos() {
+            if self.mapper.get_id(ino).is_some() && !self.open_files.contains_key(&ino) {
+                self.inodes.remove(ino);
+                    self.placeholders.remove(&ino);
+            }
+        }
+        self.cache.lock().unwrap().clear();
+        self.attr_rules.clear();
+        self.object_blocks = None;
+        Ok(())
+    }
+
+    /// Merge two commits in memory for the merge preview `ino`, creating the directories and
+    /// conflicted files of the result.
+    fn build_merge(&mut self, ino: u64, ours: git2::Oid, theirs: git2::Oid
+                  ) -> Result<Box<Inode+'static>, libc::c_int> {
+        let ns = try!(self.mapper.namespace(ino).ok_or(libc::ENOENT));
+        let name = self.merges.get(&ino).cloned()
+            .unwrap_or_else(|| format!("{}...{}", ours, theirs));
+        let merge = {
+            let labels = try!(merge::parse(&name).ok_or(libc::ENOENT));
+            try!(merge::Merge::new(&self.repos[ns], ours, theirs, (&labels.0, &labels.1)))
+        };
+
+        let filter = self.filters[ns].as_ref().map(|filter| &**filter);
+        let mapper = &mut self.mapper;
+        let inodes = &mut self.inodes;
+        let mut add = |path: &Path, inode: Box<Inode+'static>| {
+            let ino = mapper.new_ino(ns, path);
+            inodes.insert(ino, inode);
+            Id::Ino(ino)
+        };
+        Ok(merge.build(&Path::new("merge").join(&name), filter, &mut add))
+    }
+
+    /// Make sure the inode of a Git object is loaded, recreating it if caches were dropped.
+    /// References are reread once they're older than `ref_ttl`, so they're never cached any
+    /// longer than the kernel caches them.
+    fn load(&mut self, ino: u64) -> Result<(), libc::c_int> {
+        try!(self.read_refs(ino));
+        if let Some(Id::Merge(ours, theirs)) = self.mapper.get_id(ino) {
+            if !self.inodes.contains(ino) {
+                let inode = try!(self.build_merge(ino, ours, theirs));
+                self.inodes.insert(ino, inode);
+            }
+            return Ok(());
+        }
+        if self.placeholders.contains(&ino) && !self.open_files.contains_key(&ino) {
+            // Load the real object instead, once it has turned up, e.g. from a fetch
+            let ns = try!(self.mapper.namespace(ino).ok_or(libc::ENOENT));
+            if let Some(Id::Oid(oid)) = self.mapper.get_id(ino) {
+                if self.repos[ns].odb().map_or(false, |odb| odb.exists(oid)) {
+                    self.inodes.remove(ino);
+                    self.placeholders.remove(&ino);
+                }
+            }
+        }
+        if let hash_map::Entry::Vacant(entry) = self.inodes.entry(ino) {
+            let id = try!(self.mapper.get_id(ino).ok_or(libc::ENOENT));
+            let ns = try!(self.mapper.namespace(ino).ok_or(libc::ENOENT));
+            let location = self.locations.get(&ino);
+            let filter = self.filters[ns].as_ref();
+            let inode = match (id, location) {
+                (Id::Overlay(_), Some(location)) => {
+                    let found = overlay::find(&self.repos[ns], &self.layers, &location.path);
+                    try!(overlay::OverlayDir::new(&self.repos[ns], &found, &location.path, filter))
+                },
+                _ => try!(inode::new_inode(&self.repos[ns], id, &self.options, location,
+                                           &self.cache, &mut self.attr_rules, filter)),
+            };
+            if inode.is_placeholder() {
+                self.placeholders.insert(ino);
+            }
+            entry.insert(inode);
+        }
+        Ok(())
+    }
+
+    /// Get the attributes of an inode, after `init`.
+    fn attr(&mut self, ino: u64) -> Result<FileAttr, libc::c_int> {
+        let attr = self.defattr(ino);
+        if let Some(&file) = self.controls.get(&ino) {
+            return Ok(file.getattr(attr));
+        }
+
+        try!(self.load(ino));
+        let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+        let inode = self.inodes.find_mut(ino);
+        inode.and_then(|inode| inode.getattr(repo, attr))
+    }
+
+    /// Render the content of a control file.
+    fn render(&self, file: control::File) -> Vec<u8> {
+        match file {
+            control::File::Stats => {
+                let mut lines = self.stats.counters();
+                lines.push(("inodes_loaded", self.inodes.len() as u64));
+                lines.push(("inodes_mapped", self.mapper.count()));
+                lines.push(("open_files", self.open_files.len() as u64));
+                lines.extend(self.cache.lock().unwrap().counters());
+                control::format_lines(&lines)
+            },
+            control::File::Config => {
+                let root = match self.root {
+                    Some(oid) => oid.to_string(),
+                    None if !self.layers.is_empty() => {
+                        let layers: Vec<_> = self.layers.iter().map(|layer| {
+                            format!("{}={}", layer.name, layer.commit.unwrap_or(layer.tree))
+                        }).collect();
+                        format!("overlay {}", layers.join(" "))
+                    },
+                    None => "repository".to_string(),
+                };
+                let lfs = match self.options.lfs {
+                    LfsPolicy::FallbackToPointer => "pointer",
+                    LfsPolicy::Required => "error",
+                    LfsPolicy::Disabled => "off",
+                };
+                let patterns = |patterns: &[String]| match patterns.len() {
+                    0 => "none".to_string(),
+                    _ => patterns.join(" "),
+                };
+                control::format_lines(&[
+                    ("source", self.source.display().to_string()),
+                    ("repositories", self.repos.len().to_string()),
+                    ("scan", self.names.is_some().to_string()),
+                    ("root", root),
+                    ("lfs", lfs.to_string()),
+                    ("checkout", self.options.checkout.to_string()),
+                    ("filter_timeout", self.options.filter_timeout.sec.to_string()),
+                    ("hardlinks", self.options.hardlinks.to_string()),
+                    ("cache_size", self.options.cache_size.to_string()),
+                    ("object_ttl", self.options.object_ttl.sec.to_string()),
+                    ("ref_ttl", self.options.ref_ttl.sec.to_string()),
+                    ("negative_ttl", self.options.negative_ttl.sec.to_string()),
+                    ("promisor", match self.options.promisor {
+                        Some(ref path) => path.display().to_string(),
+                        None => "none".to_string(),
+                    }),
+                    ("include", patterns(&self.options.include)),
+                    ("exclude", patterns(&self.options.exclude)),
+                    ("sparse", self.options.sparse.to_string()),
+                ])
+            },
+            control::File::Version => {
+                format!("git-fs {}\n", env!("CARGO_PKG_VERSION")).into_bytes()
+            },
+            control::File::Refresh | control::File::DropCaches => Vec::new(),
+        }
+    }
+
+    /// Look up a directory entry by name, returning the attributes of what was found.
+    pub fn lookup(&mut self, parent: u64, name: &Path) -> Result<FileAttr, libc::c_int> {
+        try!(self.init());
+        self.stats.lookups += 1;
+
+        // The kernel resolves these itself, but not every caller does
+        if name == Path::new(".") {
+            return self.attr(parent);
+        } else if name == Path::new("..") {
+            let grandparent = try!(self.mapper.parent(parent).ok_or(libc::ENOENT));
+            return self.attr(grandparent);
+        }
+
+        let ns = try!(self.mapper.namespace(parent).ok_or(libc::ENOENT));
+        let id = if parent == ROOT_INO && name == Path::new(control::DIR_NAME) {
+            // NB: The control directory is found, but never listed
+            Id::Ino(self.control_dir)
+        } else {
+            try!(self.load(parent));
+            let repo = &self.repos[ns];
+            let inode = self.inodes.find_mut(parent);
+            try!(inode.and_then(|inode| inode.lookup(repo, name)))
+        };
+
+        // Track where objects are found, for checkout conversions and shared blobs
+        let location = self.child_location(ns, parent, name, id);
+        let shared = match id {
+            Id::Oid(oid) => self.shared_root(ns, location.as_ref())
+                .and_then(|root| self.links[&root].get(&oid).map(|_| root)),
+            _ => None,
+        };
+        let ino = match shared {
+            Some(root) => self.mapper.get_shared_ino(ns, id, root, parent),
+            None => self.mapper.get_ino(ns, id, parent, name),
+        };
+
+        if let Some(location) = location {
+            self.locations.entry(ino).or_insert(location);
+        }
+        if self.worktree_dirs.contains(&parent) {
+            if let Some(name) = name.to_str() {
+                self.worktrees.insert(ino, name.to_string());
+            }
+        }
+        if self.merge_dirs.contains(&parent) {
+            if let Some(name) = name.to_str() {
+                self.merges.insert(ino, name.to_string());
+            }
+        }
+        if self.inodes.contains(ino) || self.controls.contains_key(&ino) {
+            self.stats.inode_hits += 1;
+        } else {
+            self.stats.inode_misses += 1;
+        }
+
+        // NB: An existing ino may be in another namespace, e.g. from the list of repositories
+        self.attr(ino)
+    }
+
+    /// Look up a path relative to the root directory, one component at a time.
+    pub fn lookup_path(&mut self, path: &Path) -> Result<FileAttr, libc::c_int> {
+        let mut attr = try!(self.getattr(ROOT_INO));
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    attr = try!(self.lookup(attr.ino, Path::new(name)));
+                },
+                Component::ParentDir => {
+                    attr = try!(self.lookup(attr.ino, Path::new("..")));
+                },
+                Component::RootDir | Component::CurDir => (),
+                Component::Prefix(_) => return Err(libc::EINVAL),
+            }
+        }
+        Ok(attr)
+    }
+
+    /// Get the attributes of an inode.
+    pub fn getattr(&mut self, ino: u64) -> Result<FileAttr, libc::c_int> {
+        try!(self.init());
+        self.stats.getattrs += 1;
+
+        self.attr(ino)
+    }
+
+    /// Change the attributes of an inode, which may only truncate a control file that takes
+    /// commands, as opening it to be written does.  Anything else fails with `EROFS`.
+    pub fn setattr(&mut self, ino: u64, size: Option<u64>) -> Result<FileAttr, libc::c_int> {
+        try!(self.init());
+
+        match self.controls.get(&ino) {
+            Some(file) if file.is_command() && size.map_or(true, |size| size == 0) => (),
+            _ => return Err(libc::EROFS),
+        }
+        self.attr(ino)
+    }
+
+    /// Open a file, returning FUSE `FOPEN_*` flags for how it should be cached.  Opening for
+    /// writing fails with `EROFS`, except for the control files that take commands, which
+    /// may only be written.
+    pub fn open(&mut self, ino: u64, flags: u32) -> Result<u32, libc::c_int> {
+        try!(self.init());
+        self.stats.opens += 1;
+
+        let writing = flags as libc::c_int & (libc::O_ACCMODE | libc::O_TRUNC) != libc::O_RDONLY;
+        if let Some(&file) = self.controls.get(&ino) {
+            if writing && !file.is_command() {
+                return Err(libc::EROFS);
+            }
+            if !writing && file.is_command() {
+                return Err(libc::EACCES);
+            }
+            if !writing {
+                let data = self.render(file);
+                self.control_data.insert(ino, data);
+            }
+            return Ok(fuse::consts::FOPEN_DIRECT_IO);
+        }
+
+        try!(self.load(ino));
+        let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+        let inode = try!(self.inodes.find_mut(ino));
+        if writing {
+            return Err(libc::EROFS);
+        }
+        let flags = try!(inode.open(repo, flags));
+        *self.open_files.entry(ino).or_insert(0) += 1;
+        Ok(flags)
+    }
+
+    /// Write to an opened control file, asking for its command, and return how many bytes
+    /// were taken.  The command is carried out before this returns.  Writing anything else
+    /// fails with `EROFS`.
+    pub fn write(&mut self, ino: u64, _offset: u64, data: &[u8]) -> Result<u32, libc::c_int> {
+        try!(self.init());
+
+        match self.controls.get(&ino).cloned() {
+            Some(control::File::Refresh) => self.commands.refresh(),
+            Some(control::File::DropCaches) => self.commands.drop_caches(),
+            _ => return Err(libc::EROFS),
+        }
+        try!(self.run_commands());
+        Ok(data.len() as u32)
+    }
+
+    /// Check access to an inode for the given user, like `access(2)`.  Asking for write
+    /// access fails with `EROFS`, except for the control files that take commands.
+    pub fn access(&mut self, ino: u64, mask: u32, uid: u32, gid: u32) -> Result<(), libc::c_int> {
+        let attr = try!(self.getattr(ino));
+        let command = self.controls.get(&ino).map_or(false, |file| file.is_command());
+        if mask & libc::W_OK as u32 != 0 && !command {
+            return Err(libc::EROFS);
+        }
+
+        let mask = mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32;
+        let allowed = if uid == 0 {
+            // root may read and write anything, and execute anything that anyone may execute
+            let any_exec = attr.perm & 0o111 != 0 || attr.kind == FileType::Directory;
+            (libc::R_OK | libc::W_OK) as u32 | if any_exec { libc::X_OK as u32 } else { 0 }
+        } else if uid == attr.uid {
+            (attr.perm as u32 >> 6) & 0o7
+        } else if gid == attr.gid {
+            (attr.perm as u32 >> 3) & 0o7
+        } else {
+            attr.perm as u32 & 0o7
+        };
+        if mask & !allowed == 0 { Ok(()) } else { Err(libc::EACCES) }
+    }
+
+    /// List the names of an inode's extended attributes.
+    pub fn listxattr(&mut self, ino: u64) -> Result<Vec<OsString>, libc::c_int> {
+        try!(self.init());
+        self.stats.xattrs += 1;
+
+        if self.controls.contains_key(&ino) {
+            return Ok(Vec::new());
+        }
+        try!(self.load(ino));
+        let mut names = {
+            let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+            let inode = self.inodes.find_mut(ino);
+            try!(inode.and_then(|inode| inode.listxattr(repo)))
+        };
+        if let Some(location) = self.locations.get(&ino) {
+            names.push(OsString::from(XATTR_MODE));
+            if location.commit.is_some() {
+                names.push(OsString::from(XATTR_COMMIT));
+            }
+            if self.layer_name(location).is_some() {
+                names.push(OsString::from(overlay::XATTR_LAYER));
+            }
+        }
+        if let Some(worktree) = self.worktrees.get(&ino) {
+            let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+            if worktree::branch(repo, worktree).is_some() {
+                names.push(OsString::from(worktree::XATTR_BRANCH));
+            }
+            names.push(OsString::from(worktree::XATTR_WORKTREE));
+        }
+        Ok(names)
+    }
+
+    /// Get the value of one of an inode's extended attributes.
+    pub fn getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>, libc::c_int> {
+        try!(self.init());
+        self.stats.xattrs += 1;
+
+        if self.controls.contains_key(&ino) {
+            return Err(libc::ENODATA);
+        }
+        try!(self.load(ino));
+        let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+        if let Some(worktree) = self.worktrees.get(&ino) {
+            if name == OsStr::new(worktree::XATTR_BRANCH) {
+                let branch = try!(worktree::branch(repo, worktree).ok_or(libc::ENODATA));
+                return Ok(branch.into_bytes());
+            } else if name == OsStr::new(worktree::XATTR_WORKTREE) {
+                let path = try!(worktree::path(repo, worktree).ok_or(libc::ENODATA));
+                return Ok(path.into_os_string().into_vec());
+            }
+        }
+        match self.locations.get(&ino) {
+            Some(location) if name == OsStr::new(XATTR_MODE) => {
+                let mode = if location.path == Path::new("") {
+                    0o040000
+                } else {
+                    let root = try!(trace::git_errno("find_tree",
+                                                     || repo.find_tree(location.root)));
+                    let entry = try!(root.get_path(&location.path)
+                                     .map_err(|e| trace::errno("get_path", &e)));
+                    entry.filemode()
+                };
+                return Ok(format!("{:06o}", mode).into_bytes());
+            },
+            Some(&Location { commit: Some(commit), .. }) if name == OsStr::new(XATTR_COMMIT) => {
+                return Ok(commit.to_string().into_bytes());
+            },
+            Some(location) if name == OsStr::new(overlay::XATTR_LAYER) => {
+                let layer = try!(self.layer_name(location).ok_or(libc::ENODATA));
+                return Ok(layer.as_bytes().to_vec());
+            },
+            _ => (),
+        }
+        let inode = self.inodes.find_mut(ino);
+        inode.and_then(|inode| inode.getxattr(repo, name))
+    }
+
+    /// Get the totals of the filesystem.
+    pub fn statfs(&mut self) -> Result<Statfs, libc::c_int> {
+        try!(self.init());
+
+        if self.object_blocks.is_none() {
+            let bytes = self.repos.iter()
+                .map(|repo| disk_usage(&repo.path().join("objects")))
+                .fold(0, |sum, bytes| sum + bytes);
+            self.object_blocks = Some(inode::st_blocks(bytes));
+        }
+        Ok(Statfs {
+            blocks: self.object_blocks.unwrap(),
+            files: self.mapper.count(),
+            bsize: 512,
+            namelen: 255,
+        })
+    }
+
+    /// Read up to `size` bytes at `offset` from an opened file.
+    pub fn read(&mut self, ino: u64, offset: u64, size: u32) -> Result<&[u8], libc::c_int> {
+        try!(self.init());
+        self.stats.reads += 1;
+
+        let data = if self.controls.contains_key(&ino) {
+            let data = try!(self.control_data.get(&ino).ok_or(libc::EBADF));
+            let start = cmp::min(offset, data.len() as u64) as usize;
+            let end = cmp::min(start + size as usize, data.len());
+            &data[start..end]
+        } else {
+            let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+            let inode = try!(self.inodes.find_mut(ino));
+            try!(inode.read(repo, offset, size))
+        };
+        self.stats.bytes_read += data.len() as u64;
+        Ok(data)
+    }
+
+    /// Release an opened file.
+    pub fn release(&mut self, ino: u64) -> Result<(), libc::c_int> {
+        try!(self.init());
+
+        if self.controls.contains_key(&ino) {
+            self.control_data.remove(&ino);
+            return Ok(());
+        }
+        // NB: Inodes are shared by every handle, so only the last one releases its data
+        if let hash_map::Entry::Occupied(mut entry) = self.open_files.entry(ino) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+                let repo = try!(find_repo(&self.repos, &self.mapper, ino));
+                let inode = self.inodes.find_mut(ino);
+                return inode.and_then(|inode| inode.release(repo));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read directory entries, starting after the first `offset` of them, including `.` and
+    /// `..`.  Each entry is passed to `add` with its ino, its offset for resuming after it,
+    /// its type and its name.  If `add` returns true, the listing stops there.
+    pub fn readdir(&mut self, ino: u64, mut offset: u64,
+                   add: &mut FnMut(u64, u64, FileType, &Path) -> bool
+                  ) -> Result<(), libc::c_int> {
+        try!(self.init());
+        self.stats.readdirs += 1;
+
+        if self.controls.contains_key(&ino) {
+            return Err(libc::ENOTDIR);
+        }
+        try!(self.load(ino));
+        let ns = try!(self.mapper.namespace(ino).ok_or(libc::ENOENT));
+        let parent = try!(self.mapper.parent(ino).ok_or(libc::ENOENT));
+        let location = self.locations.get(&ino).cloned();
+        let shared = self.shared_root(ns, location.as_ref());
+        let all_links = &self.links;
+        let links = shared.and_then(|root| all_links.get(&root).map(|counts| (root, counts)));
+        let mapper = &mut self.mapper;
+        let repo = &self.repos[ns];
+        let inode = try!(self.inodes.find_mut(ino));
+        if offset == 0 {
+            offset += 1;
+            if add(ino, offset, FileType::Directory, &Path::new(".")) {
+                return Ok(());
+            }
+        }
+        if offset == 1 {
+            offset += 1;
+            if add(parent, offset, FileType::Directory, &Path::new("..")) {
+                return Ok(());
+            }
+        }
+        let locations = &self.locations;
+        let mut unlocated = Vec::new();
+        let result = inode.readdir(repo, offset - 2, Box::new(|id, kind, path| {
+            offset += 1;
+            let entry_ino = match (id, links) {
+                (Id::Oid(oid), Some((root, counts))) if counts.contains_key(&oid) => {
+                    mapper.get_shared_ino(ns, id, root, ino)
+                },
+                _ => mapper.get_ino(ns, id, ino, path),
+            };
+            if !locations.contains_key(&entry_ino) {
+                unlocated.push((entry_ino, id, path.to_path_buf()));
+            }
+            add(entry_ino, offset, kind, path)
+        }));
+
+        // Track where entries are like lookup does, since they may be used without one
+        for (entry_ino, id, name) in unlocated {
+            if let Some(location) = self.child_location(ns, ino, &name, id) {
+                self.locations.insert(entry_ino, location);
+            }
+        }
+        result
+    }
+
+    /// Read directory entries like `readdir`, along with the attributes of each, as for FUSE's
+    /// READDIRPLUS.  Each entry is passed to `add` with its offset, its name and its attributes,
+    /// or None if it can't be found, like the missing commit of a submodule.  The directory is
+    /// listed in one walk, and files in a Git tree get their attributes from that tree and
+    /// their object headers, without loading an inode for each.
+    pub fn readdirplus(&mut self, ino: u64, offset: u64,
+                       add: &mut FnMut(u64, &Path, Option<&FileAttr>) -> bool
+                      ) -> Result<(), libc::c_int> {
+        let mut entries = Vec::new();
+        try!(self.readdir(ino, offset, &mut |entry, next, _, name| {
+            entries.push((entry, next, name.to_path_buf()));
+            false
+        }));
+
+        let tree = self.plain_tree(ino);
+        for (entry, next, name) in entries {
+            let size = match tree {
+                Some((ns, tree)) if !self.inodes.contains(entry) => {
+                    self.blob_size(ns, tree, &name)
+                },
+                _ => None,
+            };
+            let attr = match size {
+                Some(size) => Some(blob::getattr(size, self.defattr(entry))),
+                None => self.attr(entry).ok(),
+            };
+            if add(next, &name, attr.as_ref()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the namespace and tree of a directory whose files are served just as they are in
+    /// Git, with no checkout conversions, if it is one.
+    fn plain_tree(&self, ino: u64) -> Option<(usize, git2::Oid)> {
+        if self.options.checkout || self.is_overlay(ino) || self.placeholders.contains(&ino) {
+            return None;
+        }
+        match (self.mapper.get_id(ino), self.mapper.namespace(ino)) {
+            (Some(Id::Oid(oid)), Some(ns)) => inode::peel_tree(&self.repos[ns], oid)
+                .map(|tree| (ns, tree)),
+            _ => None,
+        }
+    }
+
+    /// Get the size of the blob called `name` in a tree, from its object header, if it's
+    /// served as it is.  Blobs that may be LFS pointers need their content to tell.
+    fn blob_size(&self, ns: usize, tree: git2::Oid, name: &Path) -> Option<u64> {
+        let repo = &self.repos[ns];
+        let entry = match repo.find_tree(tree).ok().and_then(|tree| tree.get_path(name).ok()) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            return None;
+        }
+        let header = repo.odb().ok().and_then(|odb| odb.read_header(entry.id()).ok());
+        match header {
+            Some((size, _)) if self.options.lfs == LfsPolicy::Disabled ||
+                               size > lfs::MAX_POINTER_SIZE => Some(size as u64),
+            _ => None,
+        }
+    }
+}
+
+
+/// A directory of references, read from those under its prefix only when it's used
+struct RefSource {
+    prefix: String,
+    path: PathBuf,
+    /// When its references were last read, if they have been
+    read: Option<time::Timespec>,
+}
+
+
+/// Find the repository whose namespace an inode belongs to.
+fn find_repo<'a>(repos: &'a [git2::Repository], mapper: &InodeMapper, ino: u64)
+                 -> Result<&'a git2::Repository, libc::c_int> {
+    mapper.namespace(ino).map(|ns| &repos[ns]).ok_or(libc::ENOENT)
+}
+
+
+/// Add up the sizes of all files within a directory.
+fn disk_usage(dir: &Path) -> u64 {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries.filter_map(|entry| entry.ok())
+        .map(|entry| match entry.metadata() {
+            Ok(ref metadata) if metadata.is_dir() => disk_usage(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .fold(0, |sum, bytes| sum + bytes)
+}
+
+
+/// Count how many times each blob appears within a tree and its subtrees.
+fn count_blobs(repo: &git2::Repository, tree: git2::Oid,
+               counts: &mut hash_map::HashMap<git2::Oid, u32>) {
+    let tree = match repo.find_tree(tree) {
+        Ok(tree) => tree,
+        Err(_) => return,
+    };
+    for entry in tree.iter() {
+        match entry.kind() {
+            Some(git2::ObjectType::Blob) => *counts.entry(entry.id()).or_insert(0) += 1,
+            Some(git2::ObjectType::Tree) => count_blobs(repo, entry.id(), counts),
+            _ => (),
+        }
+    }
+}
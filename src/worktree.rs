@@ -0,0 +1,114 @@
+// Copyright (C) 2014  Josh Stone
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use fuse::FileType;
+use git2;
+use libc;
+use std::path::{Path, PathBuf};
+
+use inode;
+use inode::{FileAttr, Id, Inode};
+use trace;
+
+
+/// The extended attribute of the branch a worktree has checked out, unless it's detached
+pub const XATTR_BRANCH: &'static str = "user.git.branch";
+/// The extended attribute of a worktree's working directory
+pub const XATTR_WORKTREE: &'static str = "user.git.worktree";
+
+
+/// The directory of linked worktrees, each showing the tree of its own HEAD
+pub struct WorktreeDir;
+
+impl WorktreeDir {
+    pub fn new() -> Box<Inode+'static> {
+        Box::new(WorktreeDir)
+    }
+
+    /// List the worktrees by name, with the commit of each HEAD.  Worktrees with an unborn
+    /// HEAD are left out.
+    fn entries(repo: &git2::Repository) -> Vec<(String, Id)> {
+        let names = match trace::git("worktrees", || repo.worktrees()) {
+            Ok(names) => names,
+            Err(_) => return Vec::new(),
+        };
+        names.iter()
+            .filter_map(|name| name)
+            .filter_map(|name| head(repo, name).ok().map(|oid| (name.to_string(), Id::Oid(oid))))
+            .collect()
+    }
+}
+
+/// Open the repository of a linked worktree, with its own HEAD.
+fn open(repo: &git2::Repository, name: &str) -> Result<git2::Repository, libc::c_int> {
+    let worktree = try!(trace::git_errno("find_worktree", || repo.find_worktree(name)));
+    trace::git_errno("open_from_worktree", || git2::Repository::open_from_worktree(&worktree))
+}
+
+/// Find the commit of a linked worktree's HEAD.
+fn head(repo: &git2::Repository, name: &str) -> Result<git2::Oid, libc::c_int> {
+    let worktree = try!(open(repo, name));
+    let head = try!(trace::git_errno("head", || worktree.head()));
+    head.target().ok_or(libc::ENOENT)
+}
+
+/// Find the full name of the branch a linked worktree has checked out, if it's not detached.
+pub fn branch(repo: &git2::Repository, name: &str) -> Option<String> {
+    let worktree = match open(repo, name) {
+        Ok(worktree) => worktree,
+        Err(_) => return None,
+    };
+    let head = match worktree.head() {
+        Ok(head) => head,
+        Err(_) => return None,
+    };
+    if head.is_branch() {
+        head.name().map(|name| name.to_string())
+    } else {
+        None
+    }
+}
+
+/// Find the working directory of a linked worktree.
+pub fn path(repo: &git2::Repository, name: &str) -> Option<PathBuf> {
+    repo.find_worktree(name).ok().map(|worktree| worktree.path().to_path_buf())
+}
+
+impl Inode for WorktreeDir {
+    fn lookup(&mut self, repo: &git2::Repository, name: &Path
+             ) -> Result<Id, libc::c_int> {
+        WorktreeDir::entries(repo).into_iter()
+            .find(|&(ref entry, _)| name == Path::new(entry))
+            .map(|(_, id)| id)
+            .ok_or(libc::ENOENT)
+    }
+
+    fn getattr(&mut self, repo: &git2::Repository, attr: FileAttr
+              ) -> Result<FileAttr, libc::c_int> {
+        let size = WorktreeDir::entries(repo).len() as u64;
+        Ok(FileAttr {
+            size: size,
+            blocks: inode::st_blocks(size),
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2 + size as u32, // every entry is a directory
+            ..attr
+        })
+    }
+
+    fn readdir<'a>(&mut self, repo: &git2::Repository, offset: u64,
+               mut add: Box<FnMut(Id, FileType, &Path) -> bool + 'a>
+              ) -> Result<(), libc::c_int> {
+        for (name, id) in WorktreeDir::entries(repo).into_iter().skip(offset as usize) {
+            if add(id, FileType::Directory, Path::new(&name)) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
@@ -17,7 +17,7 @@ fn mounted_test_exists() {
     let file = mount.join("HEAD").join(file!());
 
     // NB: If this isn't a git checkout, we'll fail here, sorry!
-    let fs = gitfs::GitFS::new(&git_dir).unwrap();
+    let fs = gitfs::GitFS::new(&git_dir, Default::default()).unwrap();
 
     assert!(!exists(&file), "{:?} shouldn't exist before mounting!", file);
 
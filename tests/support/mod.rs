@@ -0,0 +1,254 @@
+//! Helpers to build throwaway repositories and browse them through a `gitfs::Vfs`, the same
+//! way the FUSE request handlers would, without needing a mount.
+
+#![allow(dead_code)]
+
+use gitfs;
+use git2;
+use libc;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+use gitfs::{FileAttr, FileType};
+
+
+/// Git's file modes for tree entries
+pub const BLOB: i32 = 0o100644;
+pub const EXECUTABLE: i32 = 0o100755;
+pub const SYMLINK: i32 = 0o120000;
+pub const GITLINK: i32 = 0o160000;
+const TREE: i32 = 0o040000;
+
+/// The largest read the kernel would normally request at once
+const READ_SIZE: u32 = 128 * 1024;
+
+
+/// A bare repository in a temporary directory, removed again when dropped
+pub struct TestRepo {
+    pub repo: git2::Repository,
+    path: PathBuf,
+}
+
+impl TestRepo {
+    /// Create an empty bare repository.
+    pub fn new() -> TestRepo {
+        static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+        let name = format!("gitfs-test-{}-{}", unsafe { libc::getpid() },
+                           COUNT.fetch_add(1, Ordering::SeqCst));
+        let path = env::temp_dir().join(name);
+        let repo = git2::Repository::init_bare(&path).unwrap();
+        TestRepo { repo: repo, path: path }
+    }
+
+    /// The GIT_DIR of this repository.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The file of a loose object, as written by this repository.
+    pub fn object_path(&self, oid: git2::Oid) -> PathBuf {
+        let hex = oid.to_string();
+        self.path.join("objects").join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Write a tree of entries given by their full path, creating subtrees as needed.
+    pub fn tree(&self, entries: &[(&[u8], i32, git2::Oid)]) -> git2::Oid {
+        let entries = entries.iter().map(|&(path, mode, oid)| (path.to_vec(), mode, oid));
+        self.write_tree(entries.collect())
+    }
+
+    fn write_tree(&self, entries: Vec<(Vec<u8>, i32, git2::Oid)>) -> git2::Oid {
+        let mut builder = self.repo.treebuilder(None).unwrap();
+        let mut subtrees = BTreeMap::new();
+        for (path, mode, oid) in entries {
+            match path.iter().position(|&b| b == b'/') {
+                Some(slash) => {
+                    let rest = (path[slash + 1..].to_vec(), mode, oid);
+                    subtrees.entry(path[..slash].to_vec()).or_insert_with(Vec::new).push(rest);
+                },
+                None => { builder.insert(path, oid, mode).unwrap(); },
+            }
+        }
+        for (name, entries) in subtrees {
+            let oid = self.write_tree(entries);
+            builder.insert(name, oid, TREE).unwrap();
+        }
+        builder.write().unwrap()
+    }
+
+    /// Write a blob with the given content.
+    pub fn blob(&self, data: &[u8]) -> git2::Oid {
+        self.repo.blob(data).unwrap()
+    }
+
+    /// Commit a tree on top of the given parents, updating `refname` if given.
+    pub fn commit(&self, refname: Option<&str>, tree: git2::Oid, parents: &[git2::Oid])
+                  -> git2::Oid {
+        let sig = git2::Signature::new("Test", "test@example.com",
+                                       &git2::Time::new(1234567890, 0)).unwrap();
+        let tree = self.repo.find_tree(tree).unwrap();
+        let parents: Vec<_> = parents.iter()
+            .map(|&oid| self.repo.find_commit(oid).unwrap())
+            .collect();
+        let parents: Vec<_> = parents.iter().collect();
+        self.repo.commit(refname, &sig, &sig, "test commit", &tree, &parents).unwrap()
+    }
+
+    /// Point a branch at a commit.
+    pub fn branch(&self, name: &str, commit: git2::Oid) {
+        self.repo.reference(&format!("refs/heads/{}", name), commit, true, "test").unwrap();
+    }
+
+    /// Create an annotated tag of an object.
+    pub fn tag(&self, name: &str, target: git2::Oid) -> git2::Oid {
+        let sig = git2::Signature::new("Test", "test@example.com",
+                                       &git2::Time::new(1234567890, 0)).unwrap();
+        let target = self.repo.find_object(target, None).unwrap();
+        self.repo.tag(name, &target, &sig, "test tag", true).unwrap()
+    }
+
+    /// Create a lightweight tag of an object.
+    pub fn lightweight_tag(&self, name: &str, target: git2::Oid) {
+        self.repo.reference(&format!("refs/tags/{}", name), target, true, "test").unwrap();
+    }
+
+    /// Browse this repository through a new Vfs.
+    pub fn vfs(&self) -> Vfs {
+        Vfs::new(gitfs::Vfs::new(&self.path, Default::default()).unwrap())
+    }
+
+    /// Browse a single revision of this repository through a new Vfs.
+    pub fn vfs_rev(&self, rev: &str) -> Vfs {
+        let view = gitfs::RootView::Revision(rev.to_string());
+        Vfs::new(gitfs::Vfs::new(&self.path, view).unwrap())
+    }
+
+    /// Browse revisions of this repository stacked as layers, lowest first.
+    pub fn vfs_overlay(&self, layers: &[(&str, &str)]) -> Vfs {
+        let layers = layers.iter()
+            .map(|&(name, rev)| (name.to_string(), rev.to_string()))
+            .collect();
+        Vfs::new(gitfs::Vfs::new(&self.path, gitfs::RootView::Overlay(layers)).unwrap())
+    }
+}
+
+impl Drop for TestRepo {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+
+/// A directory entry, as it would be replied to a readdir request
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub ino: u64,
+    pub offset: u64,
+    pub kind: FileType,
+    pub name: OsString,
+}
+
+
+/// Drives a `gitfs::Vfs` like the kernel would, collecting replies or errno values.
+pub struct Vfs {
+    pub vfs: gitfs::Vfs,
+}
+
+impl Vfs {
+    pub fn new(vfs: gitfs::Vfs) -> Vfs {
+        Vfs { vfs: vfs }
+    }
+
+    /// Look up every component of a path from the root.
+    pub fn stat<P: AsRef<Path>>(&mut self, path: P) -> Result<FileAttr, libc::c_int> {
+        self.vfs.lookup_path(path.as_ref())
+    }
+
+    /// List a directory, resuming with a fresh readdir after every `batch` entries,
+    /// like a reply buffer with room for only that many would.
+    pub fn readdir_batched(&mut self, ino: u64, batch: usize) -> Result<Vec<Entry>, libc::c_int> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut reply = Vec::new();
+            try!(self.vfs.readdir(ino, offset, &mut |ino, offset, kind, name| {
+                // Like fuse::ReplyDirectory, a full buffer refuses the entry
+                if reply.len() >= batch {
+                    return true;
+                }
+                reply.push(Entry {
+                    ino: ino,
+                    offset: offset,
+                    kind: kind,
+                    name: name.as_os_str().to_os_string(),
+                });
+                false
+            }));
+            match reply.last() {
+                Some(entry) => offset = entry.offset,
+                None => return Ok(entries),
+            }
+            entries.extend(reply);
+        }
+    }
+
+    /// List the directory at a path.
+    pub fn ls<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<Entry>, libc::c_int> {
+        let attr = try!(self.stat(path));
+        self.readdir_batched(attr.ino, usize::max_value())
+    }
+
+    /// List just the names in the directory at a path, without `.` and `..`.
+    pub fn names<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<OsString>, libc::c_int> {
+        let entries = try!(self.ls(path));
+        Ok(entries.into_iter()
+           .map(|entry| entry.name)
+           .filter(|name| name != OsStr::new(".") && name != OsStr::new(".."))
+           .collect())
+    }
+
+    /// Open a file and read it all, `size` bytes at a time until a read comes back empty,
+    /// then release it.
+    pub fn read_with<P: AsRef<Path>>(&mut self, path: P, size: u32)
+                                     -> Result<Vec<u8>, libc::c_int> {
+        let attr = try!(self.stat(path));
+        try!(self.vfs.open(attr.ino, 0));
+        let mut data = Vec::new();
+        let mut result = Ok(());
+        loop {
+            match self.vfs.read(attr.ino, data.len() as u64, size) {
+                Ok(chunk) if chunk.is_empty() => break,
+                Ok(chunk) => data.extend_from_slice(chunk),
+                Err(rc) => { result = Err(rc); break },
+            }
+        }
+        try!(self.vfs.release(attr.ino));
+        result.map(|()| data)
+    }
+
+    /// Open a file and read it all, then release it.
+    pub fn cat<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>, libc::c_int> {
+        self.read_with(path, READ_SIZE)
+    }
+
+    /// Open a file for writing, write `data` to it, then release it.
+    pub fn write<P: AsRef<Path>>(&mut self, path: P, data: &[u8]) -> Result<u32, libc::c_int> {
+        let attr = try!(self.stat(path));
+        try!(self.vfs.open(attr.ino, (libc::O_WRONLY | libc::O_TRUNC) as u32));
+        let result = self.vfs.write(attr.ino, 0, data);
+        try!(self.vfs.release(attr.ino));
+        result
+    }
+}
+
+
+/// Make a path from raw bytes, e.g. for names that aren't UTF-8.
+pub fn bytes_path(bytes: &[u8]) -> &Path {
+    Path::new(<OsStr as OsStrExt>::from_bytes(bytes))
+}
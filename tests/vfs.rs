@@ -0,0 +1,1389 @@
+//! Test the filesystem in-process through `gitfs::Vfs`, with throwaway repositories.
+
+extern crate flate2;
+extern crate gitfs;
+extern crate git2;
+extern crate libc;
+extern crate log;
+
+mod support;
+
+use gitfs::FileType;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use support::{TestRepo, BLOB, EXECUTABLE, GITLINK, SYMLINK, bytes_path};
+
+
+/// A repository with one commit of assorted entries on master.
+fn sample() -> (TestRepo, git2::Oid) {
+    let repo = TestRepo::new();
+    let readme = repo.blob(b"hello\n");
+    let script = repo.blob(b"#!/bin/sh\necho hi\n");
+    let target = repo.blob(b"README");
+    let nested = repo.blob(b"nested\n");
+    let latin1 = repo.blob(b"caf\xe9\n");
+    let tree = repo.tree(&[
+        (b"README", BLOB, readme),
+        (b"run.sh", EXECUTABLE, script),
+        (b"link", SYMLINK, target),
+        (b"src/lib/deep.txt", BLOB, nested),
+        (b"caf\xe9", BLOB, latin1),
+    ]);
+    let commit = repo.commit(Some("HEAD"), tree, &[]);
+    (repo, commit)
+}
+
+fn names(list: &[&str]) -> Vec<OsString> {
+    list.iter().map(OsString::from).collect()
+}
+
+
+#[test]
+fn root_layout() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let root = vfs.stat("").unwrap();
+    assert_eq!(root.ino, gitfs::ROOT_INO);
+    assert_eq!(root.kind, FileType::Directory);
+    assert_eq!(vfs.names("").unwrap(),
+               names(&["HEAD", "refs", "branches", "tags", "remotes", "archive", "worktrees",
+                       "merge"]));
+    assert_eq!(vfs.names("branches").unwrap(), names(&["master"]));
+}
+
+#[test]
+fn read_files() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    assert_eq!(vfs.cat("HEAD/README").unwrap(), b"hello\n");
+    assert_eq!(vfs.cat("branches/master/src/lib/deep.txt").unwrap(), b"nested\n");
+    assert_eq!(vfs.cat("refs/heads/master/run.sh").unwrap(), b"#!/bin/sh\necho hi\n");
+
+    // NB: symlinks are served as plain files of their target
+    assert_eq!(vfs.cat("HEAD/link").unwrap(), b"README");
+
+    let attr = vfs.stat("HEAD/README").unwrap();
+    assert_eq!(attr.kind, FileType::RegularFile);
+    assert_eq!(attr.size, 6);
+}
+
+#[test]
+fn missing_entries() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    assert_eq!(vfs.stat("HEAD/nope").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("branches/nope").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("HEAD/README/below").unwrap_err(), libc::ENOTDIR);
+    assert_eq!(vfs.ls("HEAD/README").unwrap_err(), libc::ENOTDIR);
+    assert_eq!(vfs.vfs.getattr(12345).unwrap_err(), libc::ENOENT);
+}
+
+#[test]
+fn non_utf8_names() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let name = bytes_path(b"caf\xe9");
+    assert!(vfs.names("HEAD").unwrap().iter().any(|n| n == name.as_os_str()));
+    assert_eq!(vfs.cat(bytes_path(b"HEAD/caf\xe9")).unwrap(), b"caf\xe9\n");
+}
+
+#[test]
+fn readdir_resumes() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let head = vfs.stat("HEAD").unwrap();
+    let all = vfs.readdir_batched(head.ino, usize::max_value()).unwrap();
+    assert_eq!(all.len(), 2 + 5);
+    for batch in 1..all.len() + 1 {
+        assert_eq!(vfs.readdir_batched(head.ino, batch).unwrap(), all);
+    }
+
+    // Entries agree with lookups
+    for entry in &all[2..] {
+        let path = bytes_path(b"HEAD").join(&entry.name);
+        let attr = vfs.stat(&path).unwrap();
+        assert_eq!(attr.ino, entry.ino);
+        assert_eq!(attr.kind, entry.kind);
+    }
+}
+
+#[test]
+fn huge_file() {
+    let repo = TestRepo::new();
+    let data: Vec<u8> = (0..5 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+    let big = repo.blob(&data);
+    let tree = repo.tree(&[(b"big", BLOB, big)]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let mut vfs = repo.vfs();
+
+    let attr = vfs.stat("HEAD/big").unwrap();
+    assert_eq!(attr.size, data.len() as u64);
+    assert_eq!(attr.blocks, (data.len() as u64 + 511) / 512);
+    assert!(vfs.cat("HEAD/big").unwrap() == data);
+    assert!(vfs.read_with("HEAD/big", 4000).unwrap() == data);
+}
+
+#[test]
+fn tags_are_peeled() {
+    let (repo, commit) = sample();
+    repo.tag("v1.0", commit);
+    repo.lightweight_tag("light", commit);
+    let mut vfs = repo.vfs();
+
+    let mut tags = vfs.names("tags").unwrap();
+    tags.sort();
+    assert_eq!(tags, names(&["light", "v1.0"]));
+    assert_eq!(vfs.cat("tags/v1.0/README").unwrap(), b"hello\n");
+    assert_eq!(vfs.cat("tags/light/README").unwrap(), b"hello\n");
+    // The same commit in another place is another inode
+    assert!(vfs.stat("tags/v1.0").unwrap().ino != vfs.stat("HEAD").unwrap().ino);
+}
+
+#[test]
+fn branches_with_history() {
+    let (repo, first) = sample();
+    let changed = repo.blob(b"goodbye\n");
+    let tree = repo.tree(&[(b"README", BLOB, changed)]);
+    let second = repo.commit(None, tree, &[first]);
+    repo.branch("topic/next", second);
+    let mut vfs = repo.vfs();
+
+    let mut branches = vfs.names("branches").unwrap();
+    branches.sort();
+    assert_eq!(branches, names(&["master", "topic"]));
+    assert_eq!(vfs.names("branches/topic").unwrap(), names(&["next"]));
+    assert_eq!(vfs.cat("branches/topic/next/README").unwrap(), b"goodbye\n");
+    assert_eq!(vfs.cat("branches/master/README").unwrap(), b"hello\n");
+}
+
+#[test]
+fn submodules() {
+    let repo = TestRepo::new();
+    let readme = repo.blob(b"hello\n");
+    // The commit of a submodule doesn't exist in the superproject
+    let module = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let tree = repo.tree(&[(b"README", BLOB, readme), (b"module", GITLINK, module)]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let mut vfs = repo.vfs();
+
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["README", "module"]));
+    assert_eq!(vfs.stat("HEAD/module").unwrap_err(), libc::ENOENT);
+}
+
+#[test]
+fn broken_objects() {
+    let repo = TestRepo::new();
+    let corrupt = repo.blob(b"corrupt\n");
+    let missing = repo.blob(b"missing\n");
+    let tree = repo.tree(&[(b"corrupt", BLOB, corrupt), (b"missing", BLOB, missing)]);
+    repo.commit(Some("HEAD"), tree, &[]);
+
+    // Loose objects are read-only, so replace them rather than writing over them
+    fs::remove_file(repo.object_path(corrupt)).unwrap();
+    fs::File::create(repo.object_path(corrupt)).unwrap().write_all(b"garbage").unwrap();
+    fs::remove_file(repo.object_path(missing)).unwrap();
+
+    let mut vfs = repo.vfs();
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["corrupt", "missing"]));
+    assert_eq!(vfs.stat("HEAD/corrupt").unwrap_err(), libc::EIO);
+    assert_eq!(vfs.stat("HEAD/missing").unwrap_err(), libc::ENOENT);
+}
+
+#[test]
+fn unborn_head() {
+    let repo = TestRepo::new();
+    let mut vfs = repo.vfs();
+
+    assert_eq!(vfs.names("").unwrap(),
+               names(&["refs", "branches", "tags", "remotes", "archive", "worktrees",
+                       "merge"]));
+    assert_eq!(vfs.stat("HEAD").unwrap_err(), libc::ENOENT);
+    let root = vfs.vfs.getattr(gitfs::ROOT_INO).unwrap();
+    assert_eq!((root.size, root.nlink), (7, 2 + 7));
+
+    // Resuming a listing neither repeats nor skips anything without HEAD
+    let all = vfs.ls("").unwrap();
+    assert_eq!(vfs.readdir_batched(gitfs::ROOT_INO, 1).unwrap(), all);
+    assert_eq!(vfs.readdir_batched(gitfs::ROOT_INO, 3).unwrap(), all);
+}
+
+#[test]
+fn refs_follow_ref_ttl() {
+    let (repo, first) = sample();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().ref_ttl.sec = 0;
+    assert_eq!(vfs.names("branches").unwrap(), names(&["master"]));
+
+    // References are reread once they've been cached for ref_ttl
+    repo.branch("topic", first);
+    let mut branches = vfs.names("branches").unwrap();
+    branches.sort();
+    assert_eq!(branches, names(&["master", "topic"]));
+    assert_eq!(vfs.cat("branches/topic/README").unwrap(), b"hello\n");
+    repo.repo.find_reference("refs/heads/topic").unwrap().delete().unwrap();
+    assert_eq!(vfs.stat("branches/topic").unwrap_err(), libc::ENOENT);
+}
+
+#[test]
+fn refs_read_lazily() {
+    let (repo, first) = sample();
+    repo.branch("feature/x", first);
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().ref_ttl.sec = 3600;
+    let feature = vfs.stat("branches/feature").unwrap().ino;
+    let x = vfs.stat("branches/feature/x").unwrap().ino;
+    assert_eq!(vfs.names("tags").unwrap(), names(&[]));
+
+    // After a refresh, only the directories that are used are read again
+    repo.repo.find_reference("refs/heads/feature/x").unwrap().delete().unwrap();
+    vfs.vfs.refresh().unwrap();
+    assert_eq!(vfs.names("branches").unwrap(), names(&["master"]));
+    assert_eq!(stat_counter(&mut vfs, "refreshes"), 1);
+
+    // What they listed before is forgotten once it's gone
+    assert_eq!(vfs.stat("branches/feature").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.vfs.getattr(feature).unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.vfs.getattr(x).unwrap_err(), libc::ENOENT);
+
+    // and comes back with the same number if it turns up again
+    repo.branch("feature/y", first);
+    vfs.vfs.refresh().unwrap();
+    assert_eq!(vfs.stat("branches/feature").unwrap().ino, feature);
+    assert_eq!(vfs.names("branches/feature").unwrap(), names(&["y"]));
+}
+
+#[test]
+fn revision_root() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs_rev("master:src");
+
+    assert_eq!(vfs.names("").unwrap(), names(&["lib"]));
+    assert_eq!(vfs.cat("lib/deep.txt").unwrap(), b"nested\n");
+}
+
+#[test]
+fn scan_directory() {
+    // NB: the scanned directory just borrows space within another repository
+    let outer = TestRepo::new();
+    let (first, _) = sample();
+    let (second, _) = sample();
+    let dir = outer.path().join("scan");
+    std::fs::create_dir(&dir).unwrap();
+    std::os::unix::fs::symlink(first.path(), dir.join("one.git")).unwrap();
+    std::os::unix::fs::symlink(second.path(), dir.join("two.git")).unwrap();
+    let mut vfs = support::Vfs::new(gitfs::Vfs::scan(&dir).unwrap());
+
+    assert_eq!(vfs.names("").unwrap(), names(&["one.git", "two.git"]));
+    assert_eq!(vfs.cat("one.git/HEAD/README").unwrap(), b"hello\n");
+    assert_eq!(vfs.cat("two.git/branches/master/README").unwrap(), b"hello\n");
+}
+
+#[test]
+fn read_edge_cases() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let ino = vfs.stat("HEAD/README").unwrap().ino;
+    assert_eq!(vfs.vfs.read(ino, 0, 4096).unwrap_err(), libc::EBADF);
+    vfs.vfs.open(ino, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(vfs.vfs.read(ino, 0, 0).unwrap(), b"");
+    assert_eq!(vfs.vfs.read(ino, 2, 2).unwrap(), b"ll");
+    assert_eq!(vfs.vfs.read(ino, 4, 4096).unwrap(), b"o\n");
+    assert_eq!(vfs.vfs.read(ino, 6, 4096).unwrap(), b"");
+    assert_eq!(vfs.vfs.read(ino, 1 << 40, 4096).unwrap(), b"");
+    vfs.vfs.release(ino).unwrap();
+
+    // Listing past the end is empty too
+    let head = vfs.stat("HEAD").unwrap().ino;
+    assert_eq!(vfs.vfs.readdir(head, 100, &mut |_, _, _, _| panic!("past the end")), Ok(()));
+}
+
+#[test]
+fn open_twice() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    // Releasing one handle leaves the content for the other
+    let ino = vfs.stat("HEAD/README").unwrap().ino;
+    vfs.vfs.open(ino, libc::O_RDONLY as u32).unwrap();
+    vfs.vfs.open(ino, libc::O_RDONLY as u32).unwrap();
+    vfs.vfs.release(ino).unwrap();
+    assert_eq!(vfs.vfs.read(ino, 0, 4096).unwrap(), b"hello\n");
+    vfs.vfs.release(ino).unwrap();
+    assert_eq!(vfs.vfs.read(ino, 0, 4096).unwrap_err(), libc::EBADF);
+}
+
+/// The oid of the LFS object in `lfs_repo`
+const LFS_OID: &'static str = "359b365773dbfb3e21cc1196062f477ad27f83bc04aa9dcd4178d924127a5f17";
+
+/// A repository with an LFS pointer on master, and the object it points to if `fetched`.
+fn lfs_repo(fetched: bool) -> TestRepo {
+    let repo = TestRepo::new();
+    let pointer = format!("version https://git-lfs.github.com/spec/v1\n\
+                           oid sha256:{}\n\
+                           size 12\n", LFS_OID);
+    let tree = repo.tree(&[(b"big.bin", BLOB, repo.blob(pointer.as_bytes()))]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    if fetched {
+        let dir = repo.path().join("lfs/objects").join(&LFS_OID[..2]).join(&LFS_OID[2..4]);
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join(LFS_OID)).unwrap().write_all(b"real content").unwrap();
+    }
+    repo
+}
+
+#[test]
+fn lfs_objects() {
+    // A fetched object takes the place of its pointer, whatever the policy
+    for &policy in &[gitfs::LfsPolicy::FallbackToPointer, gitfs::LfsPolicy::Required] {
+        let repo = lfs_repo(true);
+        let mut vfs = repo.vfs();
+        vfs.vfs.options_mut().lfs = policy;
+        assert_eq!(vfs.stat("HEAD/big.bin").unwrap().size, 12);
+        assert_eq!(vfs.cat("HEAD/big.bin").unwrap(), b"real content");
+        assert_eq!(vfs.read_with("HEAD/big.bin", 5).unwrap(), b"real content");
+    }
+
+    // Unless pointers are served as-is
+    let repo = lfs_repo(true);
+    let pointer = repo.repo.revparse_single("HEAD:big.bin").unwrap();
+    let pointer = pointer.as_blob().unwrap().content().to_vec();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().lfs = gitfs::LfsPolicy::Disabled;
+    assert_eq!(vfs.stat("HEAD/big.bin").unwrap().size, pointer.len() as u64);
+    assert_eq!(vfs.cat("HEAD/big.bin").unwrap(), pointer);
+}
+
+#[test]
+fn lfs_missing_objects() {
+    let repo = lfs_repo(false);
+    let pointer = repo.repo.revparse_single("HEAD:big.bin").unwrap();
+    let pointer = pointer.as_blob().unwrap().content().to_vec();
+
+    // Without the object, the pointer is served instead
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().lfs = gitfs::LfsPolicy::FallbackToPointer;
+    assert_eq!(vfs.stat("HEAD/big.bin").unwrap().size, pointer.len() as u64);
+    assert_eq!(vfs.cat("HEAD/big.bin").unwrap(), pointer);
+
+    // Or reads fail, though the size is still what the pointer says
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().lfs = gitfs::LfsPolicy::Required;
+    assert_eq!(vfs.stat("HEAD/big.bin").unwrap().size, 12);
+    assert_eq!(vfs.cat("HEAD/big.bin").unwrap_err(), libc::EIO);
+
+    // Pointers only have lowercase oids, so anything else is just a file
+    let repo = TestRepo::new();
+    let upper = format!("version https://git-lfs.github.com/spec/v1\n\
+                         oid sha256:{}\n\
+                         size 12\n", LFS_OID.to_uppercase());
+    let tree = repo.tree(&[(b"big.bin", BLOB, repo.blob(upper.as_bytes()))]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().lfs = gitfs::LfsPolicy::Required;
+    assert_eq!(vfs.cat("HEAD/big.bin").unwrap(), upper.as_bytes());
+}
+
+/// A repository with files for each kind of checkout conversion, with `--checkout` on.
+fn checkout_repo() -> (TestRepo, git2::Oid) {
+    let repo = TestRepo::new();
+    let text = repo.blob(b"one\ntwo\n");
+    let ident = repo.blob(b"id: $Id$\n");
+    let tree = repo.tree(&[
+        (b".gitattributes", BLOB, repo.blob(b"*.txt text eol=crlf\n\
+                                             *.raw -text eol=crlf\n\
+                                             *.auto text=auto eol=crlf\n\
+                                             *.id ident\n\
+                                             *.up filter=upper\n\
+                                             *.slow filter=slow\n")),
+        (b"a.txt", BLOB, text),
+        (b"a.raw", BLOB, text),
+        (b"text.auto", BLOB, text),
+        (b"binary.auto", BLOB, repo.blob(b"one\n\x00two\n")),
+        (b"a.id", BLOB, ident),
+        (b"a.up", BLOB, text),
+        (b"a.slow", BLOB, text),
+        (b"sub/.gitattributes", BLOB, repo.blob(b"*.txt -text\n")),
+        (b"sub/b.txt", BLOB, text),
+    ]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let mut config = repo.repo.config().unwrap();
+    config.set_str("filter.upper.smudge", "tr a-z A-Z").unwrap();
+    config.set_str("filter.slow.smudge", "sleep 5; cat").unwrap();
+    (repo, ident)
+}
+
+#[test]
+fn checkout_conversions() {
+    let (repo, ident) = checkout_repo();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().checkout = true;
+
+    let expected: &[(&str, &[u8])] = &[
+        ("HEAD/a.txt", b"one\r\ntwo\r\n"),
+        ("HEAD/a.raw", b"one\ntwo\n"),
+        ("HEAD/text.auto", b"one\r\ntwo\r\n"),
+        ("HEAD/binary.auto", b"one\n\x00two\n"),
+        ("HEAD/a.up", b"ONE\nTWO\n"),
+        ("HEAD/sub/b.txt", b"one\ntwo\n"),
+    ];
+    for &(path, content) in expected {
+        assert_eq!(vfs.cat(path).unwrap(), content, "{}", path);
+        // Sizes are of the converted content
+        assert_eq!(vfs.stat(path).unwrap().size, content.len() as u64, "{}", path);
+    }
+    assert_eq!(vfs.cat("HEAD/a.id").unwrap(), format!("id: $Id: {} $\n", ident).into_bytes());
+
+    // Without --checkout, blobs are served as they are
+    let mut vfs = repo.vfs();
+    assert_eq!(vfs.cat("HEAD/a.txt").unwrap(), b"one\ntwo\n");
+    assert_eq!(vfs.cat("HEAD/a.id").unwrap(), b"id: $Id$\n");
+}
+
+#[test]
+fn checkout_filter_timeout() {
+    let (repo, _) = checkout_repo();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().checkout = true;
+    vfs.vfs.options_mut().filter_timeout.sec = 0;
+    vfs.vfs.options_mut().filter_timeout.nsec = 200_000_000;
+
+    // A filter that takes too long is killed, rather than holding up everything else
+    assert_eq!(vfs.cat("HEAD/a.slow").unwrap_err(), libc::EIO);
+    assert_eq!(vfs.cat("HEAD/a.up").unwrap(), b"ONE\nTWO\n");
+}
+
+#[test]
+fn read_only() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let ino = vfs.stat("HEAD/README").unwrap().ino;
+    for &flags in &[libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+        assert_eq!(vfs.vfs.open(ino, flags as u32).unwrap_err(), libc::EROFS);
+    }
+    assert_eq!(vfs.vfs.access(ino, libc::W_OK as u32, 0, 0).unwrap_err(), libc::EROFS);
+}
+
+#[test]
+fn access() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let file = vfs.stat("HEAD/README").unwrap();
+    let dir = vfs.stat("HEAD/src").unwrap();
+    let (uid, gid) = (file.uid, file.gid);
+    let (r, x) = (libc::R_OK as u32, libc::X_OK as u32);
+
+    assert_eq!(vfs.vfs.access(file.ino, libc::F_OK as u32, uid, gid), Ok(()));
+    assert_eq!(vfs.vfs.access(file.ino, r, uid, gid), Ok(()));
+    assert_eq!(vfs.vfs.access(file.ino, x, uid, gid), Err(libc::EACCES));
+    assert_eq!(vfs.vfs.access(file.ino, r, uid + 1, gid + 1), Ok(()));
+    assert_eq!(vfs.vfs.access(file.ino, x, 0, 0), Err(libc::EACCES));
+    assert_eq!(vfs.vfs.access(dir.ino, r | x, uid + 1, gid + 1), Ok(()));
+    assert_eq!(vfs.vfs.access(dir.ino, r | x, 0, 0), Ok(()));
+}
+
+#[test]
+fn statfs() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let before = vfs.vfs.statfs().unwrap();
+    assert!(before.blocks > 0);
+    assert_eq!(before.bsize, 512);
+    vfs.stat("HEAD/src/lib/deep.txt").unwrap();
+    assert!(vfs.vfs.statfs().unwrap().files > before.files);
+}
+
+#[test]
+fn parents() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    let root = vfs.stat("").unwrap().ino;
+    let head = vfs.stat("HEAD").unwrap().ino;
+    let src = vfs.stat("HEAD/src").unwrap().ino;
+    let lib = vfs.stat("HEAD/src/lib").unwrap().ino;
+
+    assert_eq!(vfs.stat("HEAD/src/lib/..").unwrap().ino, src);
+    assert_eq!(vfs.stat("HEAD/src/lib/../..").unwrap().ino, head);
+    assert_eq!(vfs.stat("HEAD/./src/../..").unwrap().ino, root);
+    assert_eq!(vfs.stat("..").unwrap().ino, root);
+    assert_eq!(vfs.vfs.lookup(lib, ".".as_ref()).unwrap().ino, lib);
+
+    for &(dir, parent) in &[(root, root), (head, root), (src, head), (lib, src)] {
+        let entries = vfs.readdir_batched(dir, usize::max_value()).unwrap();
+        assert_eq!((entries[0].ino, &*entries[0].name), (dir, OsStr::new(".")));
+        assert_eq!((entries[1].ino, &*entries[1].name), (parent, OsStr::new("..")));
+    }
+
+    // Identical trees in different places each know their own parent
+    let master = vfs.stat("branches/master").unwrap().ino;
+    let branches = vfs.stat("branches").unwrap().ino;
+    assert!(master != head);
+    assert_eq!(vfs.stat("branches/master/..").unwrap().ino, branches);
+}
+
+#[test]
+fn directory_links() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    assert_eq!(vfs.stat("").unwrap().nlink, 2 + 8);
+    assert_eq!(vfs.stat("HEAD").unwrap().nlink, 2 + 1);
+    assert_eq!(vfs.stat("HEAD/src").unwrap().nlink, 2 + 1);
+    assert_eq!(vfs.stat("HEAD/src/lib").unwrap().nlink, 2);
+    assert_eq!(vfs.stat("branches").unwrap().nlink, 2 + 1);
+    assert_eq!(vfs.stat("HEAD/README").unwrap().nlink, 1);
+}
+
+/// A repository where the same content appears in several places.
+fn duplicates() -> TestRepo {
+    let repo = TestRepo::new();
+    let same = repo.blob(b"same\n");
+    let other = repo.blob(b"other\n");
+    let tree = repo.tree(&[
+        (b"a", BLOB, same),
+        (b"b", BLOB, same),
+        (b"dir/c", BLOB, same),
+        (b"dir/d", BLOB, other),
+    ]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    repo
+}
+
+#[test]
+fn separate_blobs() {
+    let repo = duplicates();
+    let mut vfs = repo.vfs();
+
+    let a = vfs.stat("HEAD/a").unwrap();
+    let b = vfs.stat("HEAD/b").unwrap();
+    assert!(a.ino != b.ino);
+    assert_eq!((a.nlink, b.nlink), (1, 1));
+}
+
+#[test]
+fn shared_blobs() {
+    let repo = duplicates();
+    let same = repo.blob(b"same\n");
+    let tree = repo.tree(&[(b"a", BLOB, same)]);
+    let topic = repo.commit(None, tree, &[]);
+    repo.branch("topic", topic);
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().hardlinks = true;
+
+    let a = vfs.stat("HEAD/a").unwrap();
+    assert_eq!(a.nlink, 3);
+    assert_eq!(vfs.stat("HEAD/b").unwrap().ino, a.ino);
+    assert_eq!(vfs.stat("HEAD/dir/c").unwrap().ino, a.ino);
+    assert_eq!(vfs.stat("HEAD/dir/d").unwrap().nlink, 1);
+
+    // Listings agree, but other trees are still separate
+    let entries = vfs.ls("HEAD/dir").unwrap();
+    assert_eq!(entries[2].ino, a.ino);
+    assert!(vfs.stat("branches/topic/a").unwrap().ino != a.ino);
+}
+
+#[test]
+fn stable_inodes() {
+    let (repo, first) = sample();
+    let changed = repo.blob(b"goodbye\n");
+    let tree = repo.tree(&[(b"README", BLOB, changed)]);
+    repo.branch("topic", repo.commit(None, tree, &[first]));
+    let paths = ["HEAD/src/lib/deep.txt", "branches/topic/README", "tags", "archive/HEAD.zip",
+                 "HEAD/README", "refs/heads/topic", "branches/master/src"];
+
+    let mut vfs = repo.vfs();
+    let inos: Vec<_> = paths.iter().map(|path| vfs.stat(path).unwrap().ino).collect();
+
+    // Another mount, looking in the opposite order, and with another ref added
+    repo.branch("another", first);
+    let mut vfs = repo.vfs();
+    assert!(vfs.stat("branches/another/README").is_ok());
+    for (path, &ino) in paths.iter().zip(&inos).rev() {
+        assert_eq!(vfs.stat(path).unwrap().ino, ino, "{}", path);
+    }
+
+    // Listings give the same inos as lookups
+    let root = vfs.ls("").unwrap();
+    assert_eq!(root[2].name, OsStr::new("HEAD"));
+    assert_eq!(root[2].ino, vfs.stat("HEAD").unwrap().ino);
+}
+
+#[test]
+fn xattrs() {
+    let (repo, commit) = sample();
+    let mut vfs = repo.vfs();
+    let tree = repo.repo.find_commit(commit).unwrap().tree_id();
+    let commit = commit.to_string().into_bytes();
+
+    let script = vfs.stat("HEAD/run.sh").unwrap().ino;
+    assert_eq!(vfs.vfs.listxattr(script).unwrap(),
+               names(&["user.git.oid", "user.git.type", "user.git.mode", "user.git.commit"]));
+    let oid = repo.repo.blob(b"#!/bin/sh\necho hi\n").unwrap().to_string().into_bytes();
+    assert_eq!(vfs.vfs.getxattr(script, "user.git.oid".as_ref()).unwrap(), oid);
+    assert_eq!(vfs.vfs.getxattr(script, "user.git.type".as_ref()).unwrap(), b"blob");
+    assert_eq!(vfs.vfs.getxattr(script, "user.git.mode".as_ref()).unwrap(), b"100755");
+    assert_eq!(vfs.vfs.getxattr(script, "user.git.commit".as_ref()).unwrap(), commit);
+    assert_eq!(vfs.vfs.getxattr(script, "user.other".as_ref()).unwrap_err(), libc::ENODATA);
+
+    let head = vfs.stat("HEAD").unwrap().ino;
+    assert_eq!(vfs.vfs.getxattr(head, "user.git.oid".as_ref()).unwrap(),
+               tree.to_string().into_bytes());
+    assert_eq!(vfs.vfs.getxattr(head, "user.git.type".as_ref()).unwrap(), b"tree");
+    assert_eq!(vfs.vfs.getxattr(head, "user.git.mode".as_ref()).unwrap(), b"040000");
+    let tags = vfs.stat("tags").unwrap().ino;
+    assert_eq!(vfs.vfs.listxattr(tags).unwrap(), names(&[]));
+
+    let link = vfs.stat("HEAD/link").unwrap().ino;
+    assert_eq!(vfs.vfs.getxattr(link, "user.git.mode".as_ref()).unwrap(), b"120000");
+    assert_eq!(vfs.vfs.getxattr(link, "user.git.commit".as_ref()).unwrap(), commit);
+}
+
+#[test]
+fn control_files() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+
+    // Found by name, but hidden from the listing
+    assert_eq!(vfs.stat(".git-fs").unwrap().kind, FileType::Directory);
+    assert!(!vfs.names("").unwrap().contains(&OsString::from(".git-fs")));
+    assert_eq!(vfs.names(".git-fs").unwrap(),
+               names(&["stats", "config", "version", "refresh", "drop-caches"]));
+    assert_eq!(vfs.stat(".git-fs/..").unwrap().ino, gitfs::ROOT_INO);
+
+    assert_eq!(vfs.cat("HEAD/README").unwrap(), b"hello\n");
+    let stats = String::from_utf8(vfs.cat(".git-fs/stats").unwrap()).unwrap();
+    assert!(stats.contains("bytes_read 6\n"), "{}", stats);
+    assert!(stats.lines().any(|line| line.starts_with("inodes_loaded ")));
+
+    let version = String::from_utf8(vfs.cat(".git-fs/version").unwrap()).unwrap();
+    assert!(version.starts_with("git-fs "));
+    let config = String::from_utf8(vfs.cat(".git-fs/config").unwrap()).unwrap();
+    assert!(config.contains("root repository\n"));
+    assert!(config.contains("hardlinks false\n"));
+
+    // Only the command files may be written, and not read
+    let stats = vfs.stat(".git-fs/stats").unwrap().ino;
+    let refresh = vfs.stat(".git-fs/refresh").unwrap().ino;
+    assert_eq!(vfs.vfs.open(stats, libc::O_WRONLY as u32).unwrap_err(), libc::EROFS);
+    assert_eq!(vfs.vfs.access(stats, libc::W_OK as u32, 0, 0).unwrap_err(), libc::EROFS);
+    assert_eq!(vfs.vfs.write(stats, 0, b"1").unwrap_err(), libc::EROFS);
+    assert_eq!(vfs.vfs.setattr(stats, Some(0)).unwrap_err(), libc::EROFS);
+    assert_eq!(vfs.cat(".git-fs/refresh").unwrap_err(), libc::EACCES);
+    assert_eq!(vfs.vfs.access(refresh, libc::W_OK as u32, 0, 0), Ok(()));
+    assert_eq!(vfs.vfs.setattr(refresh, Some(0)).unwrap().size, 0);
+    assert_eq!(vfs.write("HEAD/README", b"1").unwrap_err(), libc::EROFS);
+}
+
+#[test]
+fn control_commands() {
+    let (repo, first) = sample();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().ref_ttl.sec = 3600;
+    let branches = vfs.stat("branches").unwrap().ino;
+    assert_eq!(vfs.names("branches").unwrap(), names(&["master"]));
+
+    // New references only show up after a refresh, in the same directory
+    repo.branch("topic", first);
+    assert_eq!(vfs.stat("branches/topic").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.write(".git-fs/refresh", b"1\n").unwrap(), 2);
+    assert_eq!(vfs.stat("branches").unwrap().ino, branches);
+    assert_eq!(vfs.cat("branches/topic/README").unwrap(), b"hello\n");
+
+    // Signals ask for the same, which is carried out when the next operation starts
+    repo.branch("other", first);
+    vfs.vfs.commands().refresh();
+    assert_eq!(vfs.cat("branches/other/README").unwrap(), b"hello\n");
+
+    // Dropped inodes are loaded again, keeping their numbers, even while a file is open
+    let readme = vfs.stat("HEAD/README").unwrap().ino;
+    let deep = vfs.stat("HEAD/src/lib/deep.txt").unwrap().ino;
+    vfs.vfs.open(readme, libc::O_RDONLY as u32).unwrap();
+    vfs.write(".git-fs/drop-caches", b"1").unwrap();
+    assert_eq!(vfs.vfs.read(readme, 0, 4096).unwrap(), b"hello\n");
+    vfs.vfs.release(readme).unwrap();
+    assert_eq!(vfs.vfs.getattr(deep).unwrap().size, 7);
+    assert_eq!(vfs.stat("HEAD/src/lib/deep.txt").unwrap().ino, deep);
+    assert_eq!(vfs.cat("HEAD/src/lib/deep.txt").unwrap(), b"nested\n");
+
+    let stats = String::from_utf8(vfs.cat(".git-fs/stats").unwrap()).unwrap();
+    assert!(stats.contains("refreshes 2\n"));
+    assert!(stats.contains("cache_drops 1\n"));
+}
+
+/// Get one counter from `.git-fs/stats`.
+fn stat_counter(vfs: &mut support::Vfs, name: &str) -> u64 {
+    let stats = String::from_utf8(vfs.cat(".git-fs/stats").unwrap()).unwrap();
+    let line = stats.lines().find(|line| line.split(' ').next() == Some(name)).unwrap();
+    line[name.len() + 1..].parse().unwrap()
+}
+
+#[test]
+fn blob_cache() {
+    let repo = duplicates();
+    let mut vfs = repo.vfs();
+
+    // The same content is only loaded once, whatever its name, and however often it's opened
+    for path in &["HEAD/a", "HEAD/b", "HEAD/dir/c", "branches/master/a", "HEAD/a"] {
+        assert_eq!(vfs.cat(path).unwrap(), b"same\n");
+    }
+    assert_eq!(vfs.cat("HEAD/dir/d").unwrap(), b"other\n");
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_misses"), 2);
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_hits"), 4);
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_bytes"), 11);
+
+    vfs.vfs.drop_caches().unwrap();
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_bytes"), 0);
+    assert_eq!(vfs.cat("HEAD/a").unwrap(), b"same\n");
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_misses"), 3);
+}
+
+#[test]
+fn blob_cache_limit() {
+    let repo = duplicates();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().cache_size = 8;
+
+    // Only one of the blobs fits, so the least recently used one is evicted
+    assert_eq!(vfs.cat("HEAD/a").unwrap(), b"same\n");
+    assert_eq!(vfs.cat("HEAD/dir/d").unwrap(), b"other\n");
+    assert_eq!(vfs.cat("HEAD/dir/d").unwrap(), b"other\n");
+    assert_eq!(vfs.cat("HEAD/b").unwrap(), b"same\n");
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_misses"), 3);
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_bytes"), 5);
+}
+
+#[test]
+fn ttls() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+    let options = gitfs::Options::default();
+    let (object, reference) = (options.object_ttl, options.ref_ttl);
+
+    let branches = vfs.stat("branches").unwrap().ino;
+    let master = vfs.stat("branches/master").unwrap().ino;
+    let src = vfs.stat("branches/master/src").unwrap().ino;
+    let deep = vfs.stat("branches/master/src/lib/deep.txt").unwrap().ino;
+    assert!(object > reference);
+
+    // Whatever a reference names can change, but not what's in its tree
+    assert_eq!(vfs.vfs.attr_ttl(branches), reference);
+    assert_eq!(vfs.vfs.entry_ttl(branches, master), reference);
+    assert_eq!(vfs.vfs.attr_ttl(master), object);
+    assert_eq!(vfs.vfs.entry_ttl(master, src), object);
+    assert_eq!(vfs.vfs.attr_ttl(deep), object);
+
+    assert_eq!(vfs.vfs.negative_ttl(branches), options.negative_ttl);
+    assert_eq!(vfs.vfs.negative_ttl(src), object);
+
+    let vfs = repo.vfs_rev("HEAD");
+    assert_eq!(vfs.vfs.attr_ttl(gitfs::ROOT_INO), object);
+}
+
+#[test]
+fn fuse_replies() {
+    use gitfs::{Reply, Request};
+
+    let (repo, _) = sample();
+    let mut fs = gitfs::GitFS::from_vfs(repo.vfs().vfs);
+    let options = gitfs::Options::default();
+    let root = gitfs::ROOT_INO;
+
+    // Entries are cached as long as both the parent and the entry itself may be
+    let head = match fs.handle(Request::Lookup(root, Path::new("HEAD"))) {
+        Reply::Entry(ttl, attr, 1) => {
+            assert_eq!(ttl, options.ref_ttl);
+            attr.ino
+        },
+        reply => panic!("{:?}", reply),
+    };
+    let readme = match fs.handle(Request::Lookup(head, Path::new("README"))) {
+        Reply::Entry(ttl, attr, 1) => {
+            assert_eq!((ttl, attr.size), (options.object_ttl, 6));
+            attr.ino
+        },
+        reply => panic!("{:?}", reply),
+    };
+    match fs.handle(Request::Getattr(readme)) {
+        Reply::Attr(ttl, attr) => assert_eq!((ttl, attr.ino), (options.object_ttl, readme)),
+        reply => panic!("{:?}", reply),
+    }
+
+    // Missing names are negative entries with a zero inode, unless negative_ttl is 0
+    match fs.handle(Request::Lookup(head, Path::new("missing"))) {
+        Reply::Entry(ttl, attr, 0) => assert_eq!((ttl, attr.ino), (options.object_ttl, 0)),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Lookup(root, Path::new("missing"))) {
+        Reply::Entry(ttl, attr, 0) => assert_eq!((ttl, attr.ino), (options.negative_ttl, 0)),
+        reply => panic!("{:?}", reply),
+    }
+    fs.options_mut().negative_ttl.sec = 0;
+    match fs.handle(Request::Lookup(root, Path::new("missing"))) {
+        Reply::Error(rc) => assert_eq!(rc, libc::ENOENT),
+        reply => panic!("{:?}", reply),
+    }
+
+    match fs.handle(Request::Open(readme, libc::O_RDONLY as u32)) {
+        Reply::Opened(_) => (),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Read(readme, 0, 4096)) {
+        Reply::Data(data) => assert_eq!(&data[..], b"hello\n"),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Release(readme)) {
+        Reply::Ok => (),
+        reply => panic!("{:?}", reply),
+    }
+
+    // A full reply buffer stops the listing, which resumes from the last offset it took
+    let mut listed = Vec::new();
+    let mut offset = 0;
+    loop {
+        let mut batch = Vec::new();
+        match fs.handle(Request::Readdir(head, offset, &mut |_, offset, _, name| {
+            if batch.len() == 2 {
+                return true;
+            }
+            batch.push((offset, name.as_os_str().to_os_string()));
+            false
+        })) {
+            Reply::Ok => (),
+            reply => panic!("{:?}", reply),
+        }
+        match batch.last() {
+            Some(&(last, _)) => offset = last,
+            None => break,
+        }
+        listed.extend(batch.into_iter().map(|(_, name)| name));
+    }
+    let mut expected = names(&[".", ".."]);
+    expected.extend(repo.vfs().names("HEAD").unwrap());
+    assert_eq!(listed, expected);
+
+    // Extended attributes answer how big they are when asked, and ERANGE if they don't fit
+    let name = OsStr::new("user.git.type");
+    match fs.handle(Request::Getxattr(readme, name, 0)) {
+        Reply::Size(size) => assert_eq!(size, 4),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Getxattr(readme, name, 3)) {
+        Reply::Error(rc) => assert_eq!(rc, libc::ERANGE),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Getxattr(readme, name, 4)) {
+        Reply::Data(data) => assert_eq!(&data[..], b"blob"),
+        reply => panic!("{:?}", reply),
+    }
+    let list = b"user.git.oid\0user.git.type\0user.git.mode\0user.git.commit\0";
+    match fs.handle(Request::Listxattr(readme, 0)) {
+        Reply::Size(size) => assert_eq!(size as usize, list.len()),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Listxattr(readme, 1024)) {
+        Reply::Data(data) => assert_eq!(&data[..], &list[..]),
+        reply => panic!("{:?}", reply),
+    }
+
+    match fs.handle(Request::Statfs(root)) {
+        Reply::Statfs(st) => assert_eq!((st.bsize, st.namelen), (512, 255)),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Access(readme, libc::R_OK as u32, 0, 0)) {
+        Reply::Ok => (),
+        reply => panic!("{:?}", reply),
+    }
+
+    // Anything that would modify the filesystem is refused
+    for &op in &["setattr", "mkdir", "write", "setxattr"] {
+        match fs.handle(Request::Modify(op, readme)) {
+            Reply::Error(rc) => assert_eq!(rc, libc::EROFS),
+            reply => panic!("{:?}", reply),
+        }
+    }
+    match fs.handle(Request::Setattr(readme, Some(0))) {
+        Reply::Error(rc) => assert_eq!(rc, libc::EROFS),
+        reply => panic!("{:?}", reply),
+    }
+    match fs.handle(Request::Write(readme, 0, b"1")) {
+        Reply::Error(rc) => assert_eq!(rc, libc::EROFS),
+        reply => panic!("{:?}", reply),
+    }
+}
+
+/// Collects the messages logged for each operation.
+struct OpLogger {
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl log::Log for OpLogger {
+    fn enabled(&self, metadata: &log::LogMetadata) -> bool {
+        metadata.target() == "gitfs::op"
+    }
+
+    fn log(&self, record: &log::LogRecord) {
+        if self.enabled(record.metadata()) {
+            self.messages.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+}
+
+#[test]
+fn trace_ops() {
+    use gitfs::{Reply, Request};
+
+    // NB: This is the only test to install a logger, since there can only be one, but
+    // operations of other tests running meanwhile may be logged too.
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let logger = OpLogger { messages: messages.clone() };
+    log::set_logger(|max_level| {
+        max_level.set(log::LogLevelFilter::Trace);
+        Box::new(logger)
+    }).unwrap();
+
+    let (repo, _) = sample();
+    let mut fs = gitfs::GitFS::from_vfs(repo.vfs().vfs);
+    let ino = 0xdead;
+    match fs.handle(Request::Modify("symlink", ino)) {
+        Reply::Error(rc) => assert_eq!(rc, libc::EROFS),
+        reply => panic!("{:?}", reply),
+    }
+
+    // Each is logged like `op=NAME ino=INO time=MICROSECONDS errno=ERRNO`
+    let prefix = format!("op=symlink ino={} time=", ino);
+    let suffix = format!("us errno={}", libc::EROFS);
+    let messages = messages.lock().unwrap();
+    let logged: Vec<_> = messages.iter().filter(|m| m.starts_with(&prefix)).collect();
+    assert_eq!(logged.len(), 1);
+    assert!(logged[0].ends_with(&suffix), "{}", logged[0]);
+    let micros = &logged[0][prefix.len()..logged[0].len() - suffix.len()];
+    assert!(micros.parse::<u64>().is_ok(), "{}", logged[0]);
+}
+
+#[test]
+fn readdirplus() {
+    let (repo, _) = sample();
+    let mut vfs = repo.vfs();
+    let head = vfs.stat("HEAD").unwrap().ino;
+
+    let mut entries = Vec::new();
+    vfs.vfs.readdirplus(head, 0, &mut |offset, name, attr| {
+        entries.push((offset, name.to_path_buf(), attr.cloned()));
+        false
+    }).unwrap();
+    let names: Vec<_> = entries.iter().map(|entry| entry.1.clone().into_os_string()).collect();
+    assert_eq!(names, vfs.ls("HEAD").unwrap().into_iter().map(|e| e.name).collect::<Vec<_>>());
+    for &(_, ref name, ref attr) in &entries[2..] {
+        let stat = vfs.stat(Path::new("HEAD").join(name)).unwrap();
+        let attr = attr.as_ref().unwrap();
+        assert_eq!((attr.ino, attr.size, attr.kind), (stat.ino, stat.size, stat.kind));
+    }
+
+    // Resuming from any offset continues from the next entry
+    let mut rest = Vec::new();
+    vfs.vfs.readdirplus(head, entries[3].0, &mut |_, name, _| {
+        rest.push(name.to_path_buf());
+        rest.len() == 2
+    }).unwrap();
+    assert_eq!(rest, vec![entries[4].1.clone(), entries[5].1.clone()]);
+}
+
+#[test]
+fn headers_only() {
+    let repo = TestRepo::new();
+    let big = repo.blob(&vec![b'x'; 4096]);
+    let module = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+    let tree = repo.tree(&[(b"big", BLOB, big), (b"module", GITLINK, module)]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let mut vfs = repo.vfs();
+
+    // Listing with attributes doesn't load any content, or any inodes
+    let head = vfs.stat("HEAD").unwrap().ino;
+    let readdirs = stat_counter(&mut vfs, "readdirs");
+    let misses = stat_counter(&mut vfs, "inode_misses");
+    let mut sizes = Vec::new();
+    vfs.vfs.readdirplus(head, 2, &mut |_, _, attr| {
+        sizes.push(attr.map(|attr| attr.size));
+        false
+    }).unwrap();
+    assert_eq!(sizes, vec![Some(4096), None]);
+    assert_eq!(stat_counter(&mut vfs, "readdirs"), readdirs + 1);
+    assert_eq!(stat_counter(&mut vfs, "inode_misses"), misses);
+    assert_eq!(stat_counter(&mut vfs, "blob_cache_misses"), 0);
+    assert_eq!(vfs.cat("HEAD/big").unwrap().len(), 4096);
+}
+
+#[test]
+fn partial_clone() {
+    let repo = TestRepo::new();
+    let present = repo.blob(b"present\n");
+    let absent = repo.blob(b"absent\n");
+    let tree = repo.tree(&[
+        (b"present", BLOB, present),
+        (b"absent", BLOB, absent),
+        (b"dir/file", BLOB, present),
+    ]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    let dir = repo.repo.find_tree(tree).unwrap().get_path(Path::new("dir")).unwrap().id();
+    fs::remove_file(repo.object_path(absent)).unwrap();
+    fs::remove_file(repo.object_path(dir)).unwrap();
+    repo.repo.config().unwrap().set_str("extensions.partialClone", "origin").unwrap();
+
+    // Missing objects are still listed, but can't be read
+    let mut vfs = repo.vfs();
+    let file = vfs.stat("HEAD/absent").unwrap();
+    assert_eq!(file.kind, FileType::RegularFile);
+    assert_eq!(vfs.cat("HEAD/absent").unwrap_err(), libc::ENODATA);
+    assert_eq!(vfs.vfs.getxattr(file.ino, "user.git.missing".as_ref()).unwrap(), b"promisor");
+    assert_eq!(vfs.stat("HEAD/dir").unwrap().kind, FileType::Directory);
+    assert_eq!(vfs.ls("HEAD/dir").unwrap_err(), libc::ENODATA);
+    assert_eq!(vfs.cat("HEAD/present").unwrap(), b"present\n");
+
+    // With a promisor repository, they're copied from there as they're needed
+    let origin = TestRepo::new();
+    origin.blob(b"absent\n");
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().promisor = Some(origin.path().to_path_buf());
+    assert_eq!(vfs.cat("HEAD/absent").unwrap(), b"absent\n");
+    assert!(repo.object_path(absent).exists());
+    assert_eq!(vfs.ls("HEAD/dir").unwrap_err(), libc::ENODATA);
+
+    // Placeholders aren't cached like objects, and give way once the object turns up
+    let ref_ttl = vfs.vfs.options_mut().ref_ttl;
+    let placeholder = vfs.stat("HEAD/dir").unwrap();
+    assert_eq!(vfs.vfs.attr_ttl(placeholder.ino), ref_ttl);
+    assert_eq!(vfs.vfs.negative_ttl(placeholder.ino), vfs.vfs.options_mut().negative_ttl);
+    assert_eq!(repo.tree(&[(b"file", BLOB, present)]), dir);
+    assert_eq!(vfs.names("HEAD/dir").unwrap(), names(&["file"]));
+    assert!(vfs.vfs.attr_ttl(placeholder.ino) != ref_ttl);
+}
+
+#[test]
+fn worktrees() {
+    let (repo, first) = sample();
+
+    // A linked worktree gets a new branch of its own name, from HEAD
+    let path = repo.path().join("wt");
+    repo.repo.worktree("feature", &path, None).unwrap();
+    let mut vfs = repo.vfs();
+    assert_eq!(vfs.names("worktrees").unwrap(), names(&["feature"]));
+    assert_eq!(vfs.cat("worktrees/feature/README").unwrap(), b"hello\n");
+    assert_eq!(vfs.stat("worktrees/nope").unwrap_err(), libc::ENOENT);
+
+    let feature = vfs.stat("worktrees/feature").unwrap().ino;
+    assert_eq!(vfs.vfs.getxattr(feature, "user.git.branch".as_ref()).unwrap(),
+               b"refs/heads/feature");
+    assert_eq!(vfs.vfs.getxattr(feature, "user.git.worktree".as_ref()).unwrap(),
+               path.into_os_string().into_vec());
+    assert_eq!(vfs.vfs.getxattr(feature, "user.git.commit".as_ref()).unwrap(),
+               first.to_string().into_bytes());
+}
+
+/// A repository with a few top-level projects, on master.
+fn monorepo() -> TestRepo {
+    let repo = TestRepo::new();
+    let text = repo.blob(b"text\n");
+    let data = repo.blob(b"\x00\x01\x02");
+    let tree = repo.tree(&[
+        (b"README", BLOB, text),
+        (b"docs/guide.md", BLOB, text),
+        (b"src/data.bin", BLOB, data),
+        (b"src/lib/util.rs", BLOB, text),
+        (b"src/main.rs", BLOB, text),
+        (b"vendor/big.bin", BLOB, data),
+    ]);
+    repo.commit(Some("HEAD"), tree, &[]);
+    repo
+}
+
+#[test]
+fn include_exclude() {
+    let repo = monorepo();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().include = vec!["src/**".to_string()];
+    vfs.vfs.options_mut().exclude = vec!["*.bin".to_string()];
+
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["src"]));
+    assert_eq!(vfs.names("HEAD/src").unwrap(), names(&["lib", "main.rs"]));
+    assert_eq!(vfs.cat("HEAD/src/lib/util.rs").unwrap(), b"text\n");
+    assert_eq!(vfs.stat("HEAD/README").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("HEAD/src/data.bin").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("HEAD/vendor").unwrap_err(), libc::ENOENT);
+
+    // Sizes and links only count what's shown
+    let head = vfs.stat("HEAD").unwrap();
+    assert_eq!((head.size, head.nlink), (1, 3));
+    let src = vfs.stat("HEAD/src").unwrap();
+    assert_eq!((src.size, src.nlink), (2, 3));
+
+    // Archives leave out the same paths
+    let tar = vfs.cat("archive/HEAD.tar").unwrap();
+    let contains = |name: &[u8]| tar.windows(name.len()).any(|w| w == name);
+    assert!(contains(b"src/lib/util.rs"));
+    assert!(!contains(b"README"));
+    assert!(!contains(b"src/data.bin"));
+
+    let config = String::from_utf8(vfs.cat(".git-fs/config").unwrap()).unwrap();
+    assert!(config.contains("include src/**\n"));
+    assert!(config.contains("exclude *.bin\n"));
+}
+
+/// Write out an archive and unpack it with the system's own `tar` or `unzip`.
+fn unpack(repo: &TestRepo, name: &str, data: &[u8]) -> PathBuf {
+    let file = repo.path().join(name);
+    fs::File::create(&file).unwrap().write_all(data).unwrap();
+    let dir = repo.path().join(format!("{}.d", name));
+    fs::create_dir(&dir).unwrap();
+    let mut command = if name.ends_with(".zip") {
+        let mut unzip = Command::new("unzip");
+        unzip.arg("-q").arg(&file).arg("-d").arg(&dir);
+        unzip
+    } else {
+        let mut tar = Command::new("tar");
+        tar.arg(if name.ends_with(".gz") { "-xzf" } else { "-xf" }).arg(&file).arg("-C").arg(&dir);
+        tar
+    };
+    assert!(command.status().unwrap().success(), "couldn't unpack {}", name);
+    dir
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    let mut data = Vec::new();
+    fs::File::open(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn archive_formats() {
+    let (repo, first) = sample();
+    let mut vfs = repo.vfs();
+    let ref_ttl = vfs.vfs.options_mut().ref_ttl;
+    let object_ttl = vfs.vfs.options_mut().object_ttl;
+
+    let mut tree = vfs.names("HEAD").unwrap();
+    tree.sort();
+    for &name in &["HEAD.tar", "HEAD.tar.gz", "HEAD.zip"] {
+        let path = Path::new("archive").join(name);
+
+        // Sizes aren't known until an archive is opened, so they're only cached for ref_ttl
+        let attr = vfs.stat(&path).unwrap();
+        assert_eq!(attr.size, 0);
+        assert_eq!(vfs.vfs.attr_ttl(attr.ino), ref_ttl);
+
+        let data = vfs.cat(&path).unwrap();
+        let attr = vfs.stat(&path).unwrap();
+        assert_eq!(attr.size, data.len() as u64);
+        assert_eq!(vfs.vfs.attr_ttl(attr.ino), object_ttl);
+
+        let dir = unpack(&repo, name, &data);
+        let mut unpacked: Vec<_> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        unpacked.sort();
+        assert_eq!(unpacked, tree);
+        assert_eq!(read_file(&dir.join("README")), vfs.cat("HEAD/README").unwrap());
+        assert_eq!(read_file(&dir.join("run.sh")), vfs.cat("HEAD/run.sh").unwrap());
+        assert!(fs::metadata(dir.join("run.sh")).unwrap().permissions().mode() & 0o100 != 0);
+        assert_eq!(fs::read_link(dir.join("link")).unwrap(), Path::new("README"));
+        assert_eq!(read_file(&dir.join("src/lib/deep.txt")),
+                   vfs.cat("HEAD/src/lib/deep.txt").unwrap());
+        assert_eq!(read_file(&dir.join(bytes_path(b"caf\xe9"))), b"caf\xe9\n");
+    }
+
+    // The compressed tar is exactly the plain one
+    let gz = vfs.cat("archive/HEAD.tar.gz").unwrap();
+    let mut tar = Vec::new();
+    flate2::read::GzDecoder::new(&gz[..]).unwrap().read_to_end(&mut tar).unwrap();
+    assert_eq!(tar, vfs.cat("archive/HEAD.tar").unwrap());
+
+    // Sizes are remembered for any name of the same archive, even once caches are dropped
+    let zip = vfs.cat("archive/HEAD.zip").unwrap();
+    vfs.vfs.drop_caches().unwrap();
+    let attr = vfs.stat("archive/master.zip").unwrap();
+    assert_eq!((attr.size, vfs.vfs.attr_ttl(attr.ino)), (zip.len() as u64, object_ttl));
+    assert_eq!(vfs.cat("archive/master.zip").unwrap(), zip);
+
+    // Slashes and percent signs in revspecs are escaped
+    repo.branch("feature/50%", first);
+    assert!(vfs.names("archive").unwrap().contains(&OsString::from("feature%2F50%25.tar")));
+    assert_eq!(vfs.cat("archive/feature%2F50%25.tar").unwrap(),
+               vfs.cat("archive/HEAD.tar").unwrap());
+    let lib = vfs.cat("archive/master:src%2flib.tar").unwrap();
+    assert_eq!(read_file(&unpack(&repo, "lib.tar", &lib).join("deep.txt")), b"nested\n");
+}
+
+#[test]
+fn sparse_checkout() {
+    let repo = monorepo();
+
+    // Without any sparse-checkout patterns, nothing is hidden
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().sparse = true;
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["README", "docs", "src", "vendor"]));
+
+    // Cone mode, as written by `git sparse-checkout set src`
+    fs::create_dir_all(repo.path().join("info")).unwrap();
+    fs::File::create(repo.path().join("info/sparse-checkout")).unwrap()
+        .write_all(b"/*\n!/*/\n/src/\n").unwrap();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().sparse = true;
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["README", "src"]));
+    assert_eq!(vfs.names("HEAD/src").unwrap(), names(&["data.bin", "lib", "main.rs"]));
+    assert_eq!(vfs.names("HEAD/src/lib").unwrap(), names(&["util.rs"]));
+    assert_eq!(vfs.stat("HEAD/docs/guide.md").unwrap_err(), libc::ENOENT);
+
+    // Otherwise the last matching pattern decides, and directories are kept for whatever
+    // they might contain
+    fs::File::create(repo.path().join("info/sparse-checkout")).unwrap()
+        .write_all(b"/src/\n!/src/*.bin\n*.md\n").unwrap();
+    let mut vfs = repo.vfs();
+    vfs.vfs.options_mut().sparse = true;
+    assert_eq!(vfs.names("HEAD").unwrap(), names(&["docs", "src", "vendor"]));
+    assert_eq!(vfs.names("HEAD/docs").unwrap(), names(&["guide.md"]));
+    assert_eq!(vfs.names("HEAD/src").unwrap(), names(&["lib", "main.rs"]));
+    assert_eq!(vfs.names("HEAD/vendor").unwrap(), names(&[]));
+}
+
+#[test]
+fn overlay() {
+    let repo = TestRepo::new();
+    let v1 = repo.blob(b"v1\n");
+    let v2 = repo.blob(b"v2\n");
+    let fork = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v1),
+        (b"gone.txt", BLOB, v1),
+        (b"dir/a", BLOB, v1),
+        (b"dir/old", BLOB, v1),
+        (b"swap", BLOB, v1),
+    ]), &[]);
+    let main = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v2),
+        (b"gone.txt", BLOB, v2),
+        (b"dir/a", BLOB, v1),
+        (b"dir/old", BLOB, v1),
+        (b"dir/new", BLOB, v2),
+        (b"swap", BLOB, v1),
+        (b"added", BLOB, v2),
+    ]), &[fork]);
+    let feature = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v1),
+        (b"dir/a", BLOB, v2),
+        (b"dir/patch", BLOB, v2),
+        (b"swap/inner", BLOB, v2),
+        (b"top/only", BLOB, v2),
+    ]), &[fork]);
+    repo.branch("master", main);
+    repo.branch("feature", feature);
+    let mut vfs = repo.vfs_overlay(&[("base", "master"), ("top", "feature")]);
+
+    // What the top layer deleted since forking stays deleted, but not what base added
+    assert_eq!(vfs.names("").unwrap(), names(&["README", "added", "dir", "swap", "top"]));
+    assert_eq!(vfs.names("dir").unwrap(), names(&["a", "new", "patch"]));
+    assert_eq!(vfs.stat("gone.txt").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("dir/old").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("").unwrap().size, 5);
+
+    // Each name comes from the topmost layer that has it
+    assert_eq!(vfs.cat("README").unwrap(), b"v1\n");
+    assert_eq!(vfs.cat("dir/a").unwrap(), b"v2\n");
+    assert_eq!(vfs.cat("dir/new").unwrap(), b"v2\n");
+    assert_eq!(vfs.cat("added").unwrap(), b"v2\n");
+    assert_eq!(vfs.stat("swap").unwrap().kind, FileType::Directory);
+    assert_eq!(vfs.names("swap").unwrap(), names(&["inner"]));
+    assert_eq!(vfs.names("top").unwrap(), names(&["only"]));
+
+    let layer = |vfs: &mut support::Vfs, path: &str| {
+        let ino = vfs.stat(path).unwrap().ino;
+        vfs.vfs.getxattr(ino, "user.git.layer".as_ref()).unwrap()
+    };
+    assert_eq!(layer(&mut vfs, "dir"), b"top");
+    assert_eq!(layer(&mut vfs, "dir/a"), b"top");
+    assert_eq!(layer(&mut vfs, "dir/new"), b"base");
+    let new = vfs.stat("dir/new").unwrap().ino;
+    assert_eq!(vfs.vfs.getxattr(new, "user.git.commit".as_ref()).unwrap(),
+               main.to_string().into_bytes());
+    assert_eq!(vfs.vfs.getxattr(new, "user.git.mode".as_ref()).unwrap(), b"100644");
+
+    // Merged directories can be listed with attributes before they're looked up
+    let mut vfs = repo.vfs_overlay(&[("base", "master"), ("top", "feature")]);
+    let mut kinds = Vec::new();
+    vfs.vfs.readdirplus(gitfs::ROOT_INO, 2, &mut |_, name, attr| {
+        kinds.push((name.to_path_buf(), attr.map(|attr| attr.kind)));
+        false
+    }).unwrap();
+    assert_eq!(kinds[2], (Path::new("dir").to_path_buf(), Some(FileType::Directory)));
+    assert_eq!(vfs.names("dir").unwrap(), names(&["a", "new", "patch"]));
+}
+
+#[test]
+fn merge_preview() {
+    let repo = TestRepo::new();
+    let v1 = repo.blob(b"v1\n");
+    let v2 = repo.blob(b"v2\n");
+    let base = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v1),
+        (b"conflict.txt", BLOB, repo.blob(b"base\n")),
+        (b"gone.txt", BLOB, v1),
+    ]), &[]);
+    let master = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v1),
+        (b"conflict.txt", BLOB, repo.blob(b"ours\n")),
+        (b"gone.txt", BLOB, v2),
+    ]), &[base]);
+    let feature = repo.commit(None, repo.tree(&[
+        (b"README", BLOB, v2),
+        (b"conflict.txt", BLOB, repo.blob(b"theirs\n")),
+        (b"dir/new", BLOB, v2),
+    ]), &[base]);
+    let orphan = repo.commit(None, repo.tree(&[(b"README", BLOB, v2)]), &[]);
+    repo.branch("master", master);
+    repo.branch("feature", feature);
+    repo.branch("orphan", orphan);
+    let mut vfs = repo.vfs();
+
+    // Clean changes from either side are merged, and conflicts are marked with their names
+    assert_eq!(vfs.names("merge/master...feature").unwrap(),
+               names(&["CONFLICTS", "README", "conflict.txt", "dir", "gone.txt"]));
+    assert_eq!(vfs.cat("merge/master...feature/README").unwrap(), b"v2\n");
+    assert_eq!(vfs.cat("merge/master...feature/dir/new").unwrap(), b"v2\n");
+    assert_eq!(vfs.stat("merge/master...feature/dir").unwrap().kind, FileType::Directory);
+    let conflict = vfs.cat("merge/master...feature/conflict.txt").unwrap();
+    assert!(conflict.starts_with(b"<<<<<<< master\n"));
+    assert!(conflict.ends_with(b">>>>>>> feature\n"));
+    assert_eq!(vfs.cat("merge/master...feature/gone.txt").unwrap(), b"v2\n");
+    assert_eq!(vfs.cat("merge/master...feature/CONFLICTS").unwrap(),
+               b"UU conflict.txt\nUD gone.txt\n");
+    assert_eq!(vfs.cat("merge/master...master/CONFLICTS").unwrap(), b"");
+
+    // Merges are only found by name, and only with common history
+    assert_eq!(vfs.names("merge").unwrap(), names(&[]));
+    assert_eq!(vfs.stat("merge/master").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("merge/master...").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("merge/master...nope").unwrap_err(), libc::ENOENT);
+    assert_eq!(vfs.stat("merge/master...orphan").unwrap_err(), libc::ENOENT);
+
+    // Slashes in names are escaped, and conflicts are labeled with the names as they are
+    repo.branch("topic/feature", feature);
+    let conflict = vfs.cat("merge/master...topic%2Ffeature/conflict.txt").unwrap();
+    assert!(conflict.ends_with(b">>>>>>> topic/feature\n"));
+    assert_eq!(vfs.cat("merge/master...topic%2ffeature/README").unwrap(), b"v2\n");
+
+    // Dropped caches are merged again
+    vfs.vfs.drop_caches().unwrap();
+    assert_eq!(vfs.cat("merge/master...feature/README").unwrap(), b"v2\n");
+}